@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Existing tokens are treated as freshly issued so nobody is locked out by the rollout.
+ALTER TABLE subscription_tokens ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
@@ -0,0 +1,14 @@
+-- Add migration script here
+-- Transactional emails are written here in the same transaction as the change
+-- that triggers them and delivered by a background worker.
+CREATE TABLE email_outbox (
+   id uuid NOT NULL,
+   recipient TEXT NOT NULL,
+   subject TEXT NOT NULL,
+   html_content TEXT NOT NULL,
+   text_content TEXT NOT NULL,
+   n_retries SMALLINT NOT NULL DEFAULT 0,
+   execute_after timestamptz NOT NULL DEFAULT now(),
+   created_at timestamptz NOT NULL DEFAULT now(),
+   PRIMARY KEY(id)
+);
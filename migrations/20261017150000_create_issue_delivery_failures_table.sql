@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE issue_delivery_failures (
+   failure_id uuid PRIMARY KEY,
+   newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_email TEXT NOT NULL,
+   n_attempts SMALLINT NOT NULL,
+   last_error TEXT NOT NULL,
+   provider_response TEXT NULL,
+   failed_at timestamptz NOT NULL,
+   UNIQUE (newsletter_issue_id, subscriber_email)
+);
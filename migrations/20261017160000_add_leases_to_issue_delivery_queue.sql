@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE issue_delivery_queue ADD COLUMN leased_until timestamptz NULL;
+ALTER TABLE issue_delivery_queue ADD COLUMN leased_by TEXT NULL;
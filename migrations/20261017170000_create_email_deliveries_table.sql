@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE email_deliveries (
+   delivery_id uuid PRIMARY KEY,
+   newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_email TEXT NOT NULL,
+   attempt SMALLINT NOT NULL,
+   attempted_at timestamptz NOT NULL,
+   outcome TEXT NOT NULL,
+   error TEXT NULL,
+   provider_message_id TEXT NULL
+);
+CREATE INDEX email_deliveries_newsletter_issue_id_idx ON email_deliveries (newsletter_issue_id);
+CREATE INDEX email_deliveries_subscriber_email_idx ON email_deliveries (subscriber_email);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE issue_delivery_guards (
+   newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_email TEXT NOT NULL,
+   idempotency_key uuid NOT NULL,
+   created_at timestamptz NOT NULL,
+   sent_at timestamptz NULL,
+   PRIMARY KEY (newsletter_issue_id, subscriber_email)
+);
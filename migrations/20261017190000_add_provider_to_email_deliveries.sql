@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE email_deliveries ADD COLUMN provider TEXT NULL;
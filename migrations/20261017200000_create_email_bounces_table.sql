@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE email_bounces (
+   bounce_id uuid PRIMARY KEY,
+   -- `Bounce` or `SpamComplaint`, with Postmark's own id which is unique per record type.
+   record_type TEXT NOT NULL,
+   provider_bounce_id BIGINT NOT NULL,
+   bounce_type TEXT NOT NULL,
+   email TEXT NOT NULL,
+   provider_message_id TEXT NULL,
+   details TEXT NULL,
+   bounced_at timestamptz NOT NULL,
+   received_at timestamptz NOT NULL,
+   UNIQUE (record_type, provider_bounce_id)
+);
+CREATE INDEX email_bounces_email_idx ON email_bounces (email);
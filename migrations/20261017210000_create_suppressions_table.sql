@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE suppressions (
+   email TEXT PRIMARY KEY,
+   reason TEXT NOT NULL,
+   source TEXT NOT NULL,
+   created_at timestamptz NOT NULL
+);
+INSERT INTO suppressions (email, reason, source, created_at)
+SELECT lower(trim(email)), status, 'subscriptions', now()
+FROM subscriptions
+WHERE status IN ('suppressed', 'bounced', 'complained')
+ON CONFLICT DO NOTHING;
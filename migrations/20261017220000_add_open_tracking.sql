@@ -0,0 +1,10 @@
+-- Add migration script here
+ALTER TABLE newsletter_issues ADD COLUMN track_opens BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE TABLE issue_opens (
+   newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_email TEXT NOT NULL,
+   first_opened_at timestamptz NOT NULL,
+   last_opened_at timestamptz NOT NULL,
+   n_opens INTEGER NOT NULL,
+   PRIMARY KEY (newsletter_issue_id, subscriber_email)
+);
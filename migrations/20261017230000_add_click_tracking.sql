@@ -0,0 +1,10 @@
+-- Add migration script here
+ALTER TABLE newsletter_issues ADD COLUMN track_clicks BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE TABLE link_clicks (
+   click_id uuid PRIMARY KEY,
+   newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_email TEXT NOT NULL,
+   url TEXT NOT NULL,
+   clicked_at timestamptz NOT NULL
+);
+CREATE INDEX link_clicks_newsletter_issue_id_idx ON link_clicks (newsletter_issue_id);
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE email_outbox ADD COLUMN leased_until timestamptz NULL;
+ALTER TABLE email_outbox ADD COLUMN leased_by TEXT NULL;
@@ -0,0 +1,82 @@
+use super::Credentials;
+use actix_web::http::header::HeaderMap;
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use secrecy::{ExposeSecret, Secret};
+use subtle::ConstantTimeEq;
+
+/// Reads the credentials of an `Authorization: Basic` header.
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+    let header = headers
+        .get("Authorization")
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid string")?;
+    let encoded_segment = header
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'")?;
+    let decoded_bytes = general_purpose::STANDARD
+        .decode(encoded_segment)
+        .context("Failed to base64-decode 'Basic' credentials")?;
+    let decoded_credentials =
+        String::from_utf8(decoded_bytes).context("The decoded credentials are not valid UTF8")?;
+    let (username, password) = decoded_credentials
+        .split_once(':')
+        .context("A password must be provided in 'Basic' auth")?;
+    Ok(Credentials {
+        username: username.to_string(),
+        password: Secret::new(password.to_string()),
+    })
+}
+
+impl Credentials {
+    /// Compares in constant time, so response times do not give away how much of a guess was right.
+    pub fn matches(&self, expected: &Credentials) -> bool {
+        let username = self.username.as_bytes().ct_eq(expected.username.as_bytes());
+        let password = self
+            .password
+            .expose_secret()
+            .as_bytes()
+            .ct_eq(expected.password.expose_secret().as_bytes());
+        (username & password).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::basic_authentication;
+    use crate::authentication::Credentials;
+    use actix_web::http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+    use secrecy::Secret;
+
+    fn credentials(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.into(),
+            password: Secret::new(password.into()),
+        }
+    }
+
+    #[test]
+    fn basic_credentials_are_decoded_from_the_authorization_header() {
+        let mut headers = HeaderMap::new();
+        // `postmark:my-webhook-secret`
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Basic cG9zdG1hcms6bXktd2ViaG9vay1zZWNyZXQ="),
+        );
+
+        let decoded = basic_authentication(&headers).unwrap();
+
+        assert!(decoded.matches(&credentials("postmark", "my-webhook-secret")));
+    }
+
+    #[test]
+    fn credentials_only_match_if_both_username_and_password_do() {
+        let expected = credentials("postmark", "secret");
+
+        assert!(credentials("postmark", "secret").matches(&expected));
+        assert!(!credentials("postmark", "secreT").matches(&expected));
+        assert!(!credentials("postmark", "secret-but-longer").matches(&expected));
+        assert!(!credentials("admin", "secret").matches(&expected));
+    }
+}
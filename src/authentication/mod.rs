@@ -1,6 +1,8 @@
 //! src/authentication/mod.rs
 mod authentication;
+mod basic;
 mod middleware;
 pub use authentication::{change_password, validate_credentials, AuthError, Credentials};
+pub use basic::basic_authentication;
 pub use middleware::reject_anonymous_users;
 pub use middleware::UserId;
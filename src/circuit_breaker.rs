@@ -0,0 +1,123 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Takes a provider out of rotation after `failure_threshold` failures in a row.
+/// Once `cooldown` has passed a single trial request is let through: if it succeeds
+/// the provider is back in rotation, otherwise it stays out for another `cooldown`.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            state: Mutex::new(State {
+                consecutive_failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    /// Whether a request may go through right now.
+    /// When the cooldown is over only the first caller gets to try; the others keep
+    /// skipping the provider until that trial is recorded.
+    pub fn try_acquire(&self) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(open_until) if open_until > now => false,
+            Some(_) => {
+                state.open_until = Some(now + self.cooldown);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Closes the circuit. Returns `true` if it was open, i.e. the provider just recovered.
+    pub fn record_success(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let was_open = state.open_until.take().is_some();
+        state.consecutive_failures = 0;
+        was_open
+    }
+
+    /// Returns `true` if this failure opened the circuit.
+    pub fn record_failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures < self.failure_threshold {
+            return false;
+        }
+        let was_closed = state.open_until.is_none();
+        state.open_until = Some(Instant::now() + self.cooldown);
+        was_closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CircuitBreaker;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn the_circuit_opens_after_enough_failures_in_a_row() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+
+        assert!(!breaker.record_failure());
+        assert!(breaker.try_acquire());
+        assert!(breaker.record_failure());
+
+        assert!(!breaker.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn a_success_resets_the_failure_count() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+
+        assert!(breaker.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn after_the_cooldown_a_single_trial_decides_whether_the_circuit_closes() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+        assert!(breaker.record_success());
+
+        assert!(breaker.try_acquire());
+        assert!(breaker.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn a_failed_trial_opens_the_circuit_for_another_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(breaker.try_acquire());
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        breaker.record_failure();
+
+        // Counted from the failed trial, not from when it started.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!breaker.try_acquire());
+    }
+}
@@ -1,4 +1,11 @@
-use crate::{domains::SubscriberEmail, email_client::EmailClient};
+use crate::{
+    domains::SubscriberEmail,
+    email_client::{
+        EmailClient, EmailSender, FileEmailSender, PostmarkEmailSender, SmtpEmailSender, SmtpTls,
+        StdoutEmailSender, MAX_BATCH_SIZE,
+    },
+    rate_limiter::{DomainRateLimiter, RateLimiter},
+};
 use config::File;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
@@ -6,6 +13,7 @@ use sqlx::{
     postgres::{PgConnectOptions, PgSslMode},
     ConnectOptions,
 };
+use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(serde::Deserialize)]
@@ -13,16 +21,195 @@ pub struct Settings {
     pub application: ApplicationSettings,
     pub database: DatabaseSettings,
     pub email_client: EmailClientSettings,
+    pub delivery: DeliverySettings,
+    pub worker: WorkerSettings,
     pub env: String,
     pub redis_uri: Secret<String>,
 }
 
 #[derive(serde::Deserialize)]
 pub struct EmailClientSettings {
-    pub base_url: String,
+    /// The provider tried first.
+    #[serde(flatten)]
+    pub provider: ProviderSettings,
+    /// Tried in order when the providers before them fail with a transient error
+    /// or are out of rotation.
+    #[serde(default)]
+    pub fallback_providers: Vec<ProviderSettings>,
     pub sender_email: String,
-    pub authorization_token: Secret<String>,
     pub timeout_milliseconds: u64,
+    pub rate_limit: Option<RateLimitSettings>,
+    #[serde(default)]
+    pub domain_rate_limits: Vec<DomainRateLimitSettings>,
+    pub circuit_breaker: Option<CircuitBreakerSettings>,
+    pub webhook: WebhookSettings,
+}
+
+/// The basic auth credentials Postmark is configured to send along with its webhooks.
+#[derive(serde::Deserialize, Clone)]
+pub struct WebhookSettings {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ProviderSettings {
+    /// What deliveries are tagged with; defaults to the kind.
+    pub name: Option<String>,
+    #[serde(default)]
+    pub kind: EmailClientKind,
+    /// Required when `kind` is `postmark`.
+    pub base_url: Option<String>,
+    /// Required when `kind` is `postmark`.
+    pub authorization_token: Option<Secret<String>>,
+    /// Required when `kind` is `smtp`.
+    pub smtp: Option<SmtpSettings>,
+    /// Where `.eml` files go when `kind` is `file`.
+    pub file_directory: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CircuitBreakerSettings {
+    /// Transient failures in a row that take a provider out of rotation.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_threshold: u32,
+    /// How long it stays out before a single email is sent to see whether it recovered.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cooldown_milliseconds: u64,
+}
+
+/// Which backend sends the emails.
+#[derive(serde::Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailClientKind {
+    /// Postmark's HTTP API, using `base_url` and `authorization_token`.
+    #[default]
+    Postmark,
+    Smtp,
+    /// Writes every email to `file_directory`, for local development.
+    File,
+    /// Logs every email, for local development.
+    Stdout,
+}
+
+impl EmailClientKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailClientKind::Postmark => "postmark",
+            EmailClientKind::Smtp => "smtp",
+            EmailClientKind::File => "file",
+            EmailClientKind::Stdout => "stdout",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    pub tls: SmtpTls,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RateLimitSettings {
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub messages_per_second: f64,
+    #[serde(deserialize_with = "deserialize_burst")]
+    pub burst: u32,
+}
+
+#[derive(serde::Deserialize)]
+pub struct DomainRateLimitSettings {
+    pub domain: String,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub messages_per_second: f64,
+    #[serde(deserialize_with = "deserialize_burst")]
+    pub burst: u32,
+}
+
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let rate = <f64 as serde::Deserialize>::deserialize(deserializer)?;
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(serde::de::Error::custom(format!(
+            "messages_per_second must be a positive number, got {rate}"
+        )));
+    }
+    Ok(rate)
+}
+
+fn deserialize_burst<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match <u32 as serde::Deserialize>::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("burst must be at least 1")),
+        burst => Ok(burst),
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DeliverySettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: i16,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_base_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub batch_size: usize,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub lease_duration_milliseconds: u64,
+    /// Lets issues published with open tracking carry a tracking image.
+    /// When off no issue gets one, whatever it was published with.
+    #[serde(default)]
+    pub track_opens: bool,
+    /// Same for rewriting the links of issues published with click tracking.
+    #[serde(default)]
+    pub track_clicks: bool,
+}
+
+impl DeliverySettings {
+    /// How many emails of one issue go out per provider call; 1 sends them one by one.
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.clamp(1, MAX_BATCH_SIZE)
+    }
+
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_base_delay_milliseconds)
+    }
+
+    /// How long a worker may hold a task before others can claim it. Workers renew
+    /// their leases while sending, so this only bounds how long a crashed worker's tasks wait.
+    pub fn lease_duration(&self) -> Duration {
+        Duration::from_millis(self.lease_duration_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct WorkerSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub concurrency: usize,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub idle_poll_interval_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub error_backoff_milliseconds: u64,
+}
+
+impl WorkerSettings {
+    /// The longest an idle worker waits before polling the queue again. It wakes up earlier
+    /// when notified of a new issue or when a retry or deferred task becomes due.
+    pub fn idle_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.idle_poll_interval_milliseconds)
+    }
+
+    /// How long a worker waits after an unexpected error, e.g. the database being unreachable.
+    pub fn error_backoff(&self) -> Duration {
+        Duration::from_millis(self.error_backoff_milliseconds)
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -43,6 +230,33 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub base_url: String,
     pub hmac_secret: Secret<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscription_token_ttl_hours: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscription_token_retention_hours: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub shutdown_timeout_seconds: u64,
+}
+
+impl ApplicationSettings {
+    pub fn subscription_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.subscription_token_ttl_hours as i64)
+    }
+
+    /// How long tokens are kept before being deleted. Past their TTL they still let the
+    /// subscriber ask for a new confirmation email, so this is never shorter than the TTL.
+    pub fn subscription_token_retention(&self) -> chrono::Duration {
+        chrono::Duration::hours(
+            self.subscription_token_retention_hours
+                .max(self.subscription_token_ttl_hours) as i64,
+        )
+    }
+
+    /// How long in-flight requests and deliveries get to finish once shutdown
+    /// is requested before the process exits anyway.
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_seconds)
+    }
 }
 
 impl DatabaseSettings {
@@ -139,11 +353,121 @@ impl EmailClientSettings {
     pub fn client(&self) -> EmailClient {
         let sender_email = self.sender().expect("Invalid sender email address");
         let timeout = self.timeout();
-        EmailClient::new(
-            self.base_url.clone(),
-            sender_email,
-            self.authorization_token.clone(),
-            timeout,
+        let mut client = EmailClient::new(
+            self.provider.name(),
+            self.provider.sender(sender_email.clone(), timeout),
+        );
+        for provider in &self.fallback_providers {
+            client = client.with_fallback(
+                provider.name(),
+                provider.sender(sender_email.clone(), timeout),
+            );
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            client = client.with_circuit_breaker(
+                circuit_breaker.failure_threshold,
+                Duration::from_millis(circuit_breaker.cooldown_milliseconds),
+            );
+        }
+        let client = client.with_domain_rate_limiter(DomainRateLimiter::new(
+            self.domain_rate_limits.iter().map(|limit| {
+                (
+                    limit.domain.clone(),
+                    RateLimiter::new(limit.messages_per_second, limit.burst),
+                )
+            }),
+        ));
+        match &self.rate_limit {
+            Some(limit) => client.with_rate_limiter(Arc::new(RateLimiter::new(
+                limit.messages_per_second,
+                limit.burst,
+            ))),
+            None => client,
+        }
+    }
+}
+
+impl ProviderSettings {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.kind.as_str().to_string())
+    }
+
+    pub fn sender(&self, sender_email: SubscriberEmail, timeout: Duration) -> Box<dyn EmailSender> {
+        match self.kind {
+            EmailClientKind::Postmark => Box::new(PostmarkEmailSender::new(
+                self.base_url
+                    .clone()
+                    .expect("`base_url` is required when `kind` is `postmark`"),
+                sender_email,
+                self.authorization_token
+                    .clone()
+                    .expect("`authorization_token` is required when `kind` is `postmark`"),
+                timeout,
+            )),
+            EmailClientKind::Smtp => {
+                let smtp = self
+                    .smtp
+                    .as_ref()
+                    .expect("`smtp` is required when `kind` is `smtp`");
+                let credentials = match (&smtp.username, &smtp.password) {
+                    (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+                    _ => None,
+                };
+                Box::new(
+                    SmtpEmailSender::new(
+                        &smtp.host,
+                        smtp.port,
+                        smtp.tls,
+                        credentials,
+                        sender_email,
+                        timeout,
+                    )
+                    .expect("Invalid SMTP settings"),
+                )
+            }
+            EmailClientKind::File => Box::new(FileEmailSender::new(
+                self.file_directory
+                    .as_ref()
+                    .expect("`file_directory` is required when `kind` is `file`")
+                    .into(),
+                sender_email,
+            )),
+            EmailClientKind::Stdout => Box::new(StdoutEmailSender),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimitSettings, WorkerSettings};
+
+    #[test]
+    fn rate_limits_must_let_some_email_through() {
+        for settings in [
+            r#"{"messages_per_second": 0, "burst": 10}"#,
+            r#"{"messages_per_second": -1.5, "burst": 10}"#,
+            r#"{"messages_per_second": 10, "burst": 0}"#,
+        ] {
+            assert!(serde_json::from_str::<RateLimitSettings>(settings).is_err());
+        }
+        assert!(serde_json::from_str::<RateLimitSettings>(
+            r#"{"messages_per_second": 0.5, "burst": 1}"#
         )
+        .is_ok());
+    }
+
+    #[test]
+    fn numbers_can_be_overridden_from_environment_variables() {
+        // Environment variables always come through as strings.
+        let settings: WorkerSettings = serde_json::from_str(
+            r#"{"concurrency": "8", "idle_poll_interval_milliseconds": "5000", "error_backoff_milliseconds": "250"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.concurrency, 8);
+        assert_eq!(settings.idle_poll_interval_milliseconds, 5000);
+        assert_eq!(settings.error_backoff_milliseconds, 250);
     }
 }
@@ -1,178 +0,0 @@
-use crate::domains::SubscriberEmail;
-use reqwest::Client;
-use secrecy::{ExposeSecret, Secret};
-use serde::{Deserialize, Serialize};
-use std::time::Duration;
-
-pub struct EmailClient {
-    client: Client,
-    base_url: String,
-    sender_email: SubscriberEmail,
-    authorization_token: Secret<String>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct SendEmailRequest<'a> {
-    from: &'a str,
-    to: &'a str,
-    subject: &'a str,
-    html_body: &'a str,
-    text_body: &'a str,
-}
-
-impl EmailClient {
-    pub fn new(
-        base_url: String,
-        sender_email: SubscriberEmail,
-        authorization_token: Secret<String>,
-        timeout_milliseconds: Duration,
-    ) -> Self {
-        Self {
-            client: Client::builder()
-                .timeout(timeout_milliseconds)
-                .build()
-                .unwrap(),
-            base_url,
-            sender_email,
-            authorization_token,
-        }
-    }
-
-    pub async fn send_email(
-        &self,
-        recipient: &SubscriberEmail,
-        subject: &str,
-        html_content: &str,
-        text_content: &str,
-    ) -> Result<(), reqwest::Error> {
-        let url = format!("{}/email", self.base_url);
-        let request_body = SendEmailRequest {
-            to: recipient.as_ref(),
-            from: self.sender_email.as_ref(),
-            subject,
-            html_body: html_content,
-            text_body: text_content,
-        };
-
-        self.client
-            .post(url)
-            .header(
-                "X-Postmark-Server-Token",
-                self.authorization_token.expose_secret(),
-            )
-            .json(&request_body)
-            .send()
-            .await?
-            .error_for_status()?;
-        Ok(())
-    }
-}
-
-#[cfg(test)]
-mod tests {
-
-    use crate::domains::SubscriberEmail;
-    use crate::email_client::EmailClient;
-    use claims::{assert_err, assert_ok};
-    use fake::faker::internet::en::SafeEmail;
-    use fake::faker::lorem::en::{Paragraph, Sentence};
-    use fake::{Fake, Faker};
-    use secrecy::Secret;
-    use std::time::Duration;
-    use wiremock::matchers::{any, header, header_exists, method, path};
-    use wiremock::{Match, Mock, MockServer, Request, ResponseTemplate};
-
-    struct SendEmailRequestBodyMatcher;
-    impl Match for SendEmailRequestBodyMatcher {
-        fn matches(&self, request: &Request) -> bool {
-            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
-            if let Ok(body) = result {
-                body.get("From").is_some()
-                    && body.get("To").is_some()
-                    && body.get("Subject").is_some()
-                    && body.get("HtmlBody").is_some()
-                    && body.get("TextBody").is_some()
-            } else {
-                false
-            }
-        }
-    }
-
-    fn email() -> SubscriberEmail {
-        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
-    }
-
-    fn subject() -> String {
-        Sentence(1..2).fake()
-    }
-
-    fn paragraph() -> String {
-        Paragraph(1..2).fake()
-    }
-
-    fn email_client(base_url: String) -> EmailClient {
-        EmailClient::new(
-            base_url,
-            email(),
-            Secret::new(Faker.fake()),
-            Duration::from_millis(100),
-        )
-    }
-
-    #[tokio::test]
-    async fn send_email_fires_a_request_to_base_url() {
-        let mock_server = MockServer::start().await;
-        let email_client = email_client(mock_server.uri());
-
-        Mock::given(header_exists("X-Postmark-Server-Token"))
-            .and(header("Content-Type", "application/json"))
-            .and(path("/email"))
-            .and(method("POST"))
-            .and(SendEmailRequestBodyMatcher)
-            .respond_with(ResponseTemplate::new(200))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let outcome = email_client
-            .send_email(&email(), &subject(), &paragraph(), &paragraph())
-            .await;
-        assert_ok!(outcome);
-    }
-
-    #[tokio::test]
-    async fn send_email_fails_if_server_responds_500() {
-        let mock_server = MockServer::start().await;
-        let email_client = email_client(mock_server.uri());
-
-        Mock::given(any())
-            .respond_with(ResponseTemplate::new(500))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let outcome = email_client
-            .send_email(&email(), &subject(), &paragraph(), &paragraph())
-            .await;
-        assert_err!(outcome);
-    }
-
-    #[tokio::test]
-    async fn test_if_server_takes_too_long() {
-        let mock_server = MockServer::start().await;
-        let email_client = email_client(mock_server.uri());
-
-        Mock::given(any())
-            .respond_with(ResponseTemplate::new(500).set_delay(Duration::from_secs(180)))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let outcome = email_client
-            .send_email(&email(), &subject(), &paragraph(), &paragraph())
-            .await;
-        // Assert
-        assert_err!(outcome);
-    }
-}
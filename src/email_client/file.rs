@@ -0,0 +1,77 @@
+use super::smtp::build_message;
+use super::{EmailSender, OutgoingEmail, SendEmailError, SentEmail};
+use crate::domains::SubscriberEmail;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Writes every email as an `.eml` file into a directory instead of sending it.
+pub struct FileEmailSender {
+    directory: PathBuf,
+    sender_email: SubscriberEmail,
+}
+
+impl FileEmailSender {
+    pub fn new(directory: PathBuf, sender_email: SubscriberEmail) -> Self {
+        Self {
+            directory,
+            sender_email,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for FileEmailSender {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<SentEmail, SendEmailError> {
+        let (message, message_id) = build_message(&self.sender_email, email)?;
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let path = self.directory.join(format!("{}.eml", Uuid::new_v4()));
+        tokio::fs::write(&path, message.formatted()).await?;
+        tracing::info!(path = %path.display(), "Wrote an email to disk");
+        Ok(SentEmail {
+            message_id: Some(message_id),
+            provider: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileEmailSender;
+    use crate::domains::SubscriberEmail;
+    use crate::email_client::{EmailSender, OutgoingEmail};
+    use claims::assert_ok;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn every_email_becomes_an_eml_file() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let sender = FileEmailSender::new(
+            directory.clone(),
+            SubscriberEmail::parse("newsletter@example.com".into()).unwrap(),
+        );
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+
+        assert_ok!(
+            sender
+                .send(&OutgoingEmail {
+                    recipient: &recipient,
+                    subject: "Our latest issue",
+                    html_content: "<p>Hello</p>",
+                    text_content: "Hello",
+                    headers: &[],
+                    idempotency_key: None,
+                })
+                .await
+        );
+
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let contents = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(contents.contains("Subject: Our latest issue"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
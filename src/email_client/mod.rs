@@ -0,0 +1,661 @@
+mod file;
+mod postmark;
+mod smtp;
+mod stdout;
+
+pub use file::FileEmailSender;
+pub use postmark::{PostmarkEmailSender, MAX_BATCH_SIZE};
+pub use smtp::{SmtpEmailSender, SmtpTls};
+pub use stdout::StdoutEmailSender;
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::domains::SubscriberEmail;
+use crate::rate_limiter::{DomainRateLimiter, RateLimiter};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader {
+    pub name: String,
+    pub value: String,
+}
+
+/// One message handed to an [`EmailSender`].
+pub struct OutgoingEmail<'a> {
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub headers: &'a [EmailHeader],
+    /// Stays the same across retries of one delivery. It becomes the `Message-ID`,
+    /// so mailbox providers can drop a copy we sent twice, and is attached as metadata.
+    pub idempotency_key: Option<&'a str>,
+}
+
+/// What the provider told us about an email it accepted.
+#[derive(Debug, Default)]
+pub struct SentEmail {
+    /// The provider's id for the message, which its activity feed and webhooks refer to.
+    pub message_id: Option<String>,
+    /// Which of the configured providers sent it. Set by [`EmailClient`].
+    pub provider: Option<String>,
+}
+
+impl EmailHeader {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+}
+
+/// How a caller should react to a failed send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendEmailErrorKind {
+    /// Timeouts, rate limiting and provider outages: try again later.
+    Transient,
+    /// The provider will never deliver to this address: stop sending to it.
+    PermanentRecipient,
+    /// The provider refused this one email, e.g. as invalid. Retrying will not help,
+    /// but neither the address nor the other emails are to blame.
+    PermanentMessage,
+    /// Our token, sender or request is wrong: nothing will succeed until someone fixes it.
+    Configuration,
+}
+
+/// The error body Postmark returns alongside any 4xx status.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PostmarkError {
+    pub error_code: i64,
+    pub message: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SendEmailError {
+    #[error("Failed to send the request to the email provider")]
+    Request(#[from] reqwest::Error),
+    #[error("The email provider rejected the email with status {status}: {body}")]
+    Rejected {
+        status: StatusCode,
+        error: Option<PostmarkError>,
+        body: String,
+        /// Set when the provider is rate limiting us.
+        retry_after: Option<Duration>,
+    },
+    #[error("The email provider rejected the email with error code {}: {}", .0.error_code, .0.message)]
+    MessageRejected(PostmarkError),
+    #[error("The SMTP server did not accept the email")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("The recipient address cannot be used in an email")]
+    InvalidRecipient(#[source] anyhow::Error),
+    #[error("Failed to build the email")]
+    Message(#[source] anyhow::Error),
+    #[error("Failed to write the email")]
+    Io(#[from] std::io::Error),
+    #[error("Every email provider is out of rotation after failing repeatedly")]
+    NoProviderAvailable,
+    #[error("The email provider did not say whether it accepted the email")]
+    MissingOutcome,
+}
+
+impl SendEmailError {
+    pub fn kind(&self) -> SendEmailErrorKind {
+        match self {
+            SendEmailError::Request(e) if e.is_builder() => SendEmailErrorKind::Configuration,
+            SendEmailError::Request(_) => SendEmailErrorKind::Transient,
+            SendEmailError::Rejected { status, .. }
+                if status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS =>
+            {
+                SendEmailErrorKind::Transient
+            }
+            SendEmailError::Rejected {
+                status,
+                error: Some(error),
+                ..
+            } if *status == StatusCode::UNPROCESSABLE_ENTITY => {
+                postmark::error_kind(error.error_code)
+            }
+            // Wrong token, wrong URL...
+            SendEmailError::Rejected { .. } => SendEmailErrorKind::Configuration,
+            SendEmailError::MessageRejected(error) => postmark::error_kind(error.error_code),
+            SendEmailError::Smtp(e) => smtp::error_kind(e),
+            SendEmailError::InvalidRecipient(_) => SendEmailErrorKind::PermanentRecipient,
+            SendEmailError::Message(_) => SendEmailErrorKind::Configuration,
+            SendEmailError::Io(_)
+            | SendEmailError::NoProviderAvailable
+            | SendEmailError::MissingOutcome => SendEmailErrorKind::Transient,
+        }
+    }
+
+    /// What the provider answered, if it answered at all.
+    pub fn provider_response(&self) -> Option<String> {
+        match self {
+            SendEmailError::Request(_) => None,
+            SendEmailError::Rejected { status, body, .. } if body.is_empty() => {
+                Some(status.to_string())
+            }
+            SendEmailError::Rejected { status, body, .. } => Some(format!("{status}: {body}")),
+            SendEmailError::MessageRejected(error) => {
+                Some(format!("{}: {}", error.error_code, error.message))
+            }
+            SendEmailError::Smtp(e) if e.status().is_some() => Some(e.to_string()),
+            SendEmailError::Smtp(_)
+            | SendEmailError::InvalidRecipient(_)
+            | SendEmailError::Message(_)
+            | SendEmailError::Io(_)
+            | SendEmailError::NoProviderAvailable
+            | SendEmailError::MissingOutcome => None,
+        }
+    }
+
+    /// How long the provider asked us to hold off, if it is rate limiting us.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SendEmailError::Rejected { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// A way of getting emails out. `EmailClientSettings::kind` picks the implementation.
+#[async_trait::async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<SentEmail, SendEmailError>;
+
+    /// Sends up to [`EmailSender::max_batch_size`] emails at once. The outer error means nothing
+    /// was sent; otherwise there is one result per email, in order.
+    /// Backends without a batch API send them one after the other.
+    async fn send_batch(
+        &self,
+        emails: &[OutgoingEmail<'_>],
+    ) -> Result<Vec<Result<SentEmail, SendEmailError>>, SendEmailError> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for email in emails {
+            outcomes.push(self.send(email).await);
+        }
+        Ok(outcomes)
+    }
+
+    fn max_batch_size(&self) -> usize {
+        1
+    }
+
+    /// Reserves a slot in the budget of the recipient's domain, or returns how long
+    /// until that domain accepts more. Callers are expected to send to someone else meanwhile.
+    fn try_reserve_for(&self, _recipient: &str) -> Result<(), Duration> {
+        Ok(())
+    }
+
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<SentEmail, SendEmailError> {
+        self.send_email_with_headers(recipient, subject, html_content, text_content, &[])
+            .await
+    }
+
+    async fn send_email_with_headers(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[EmailHeader],
+    ) -> Result<SentEmail, SendEmailError> {
+        self.send(&OutgoingEmail {
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            headers,
+            idempotency_key: None,
+        })
+        .await
+    }
+}
+
+/// The `Message-ID` of a delivery, stable across its retries.
+fn message_id(idempotency_key: &str, sender: &SubscriberEmail) -> String {
+    let domain = sender.as_ref().rsplit('@').next().unwrap_or_default();
+    format!("<{idempotency_key}@{domain}>")
+}
+
+/// Makes sure a batch has exactly one outcome per email. Emails the provider said nothing
+/// about are treated as transient failures; those it did accept are guarded against resending.
+pub fn one_outcome_per_email(
+    mut outcomes: Vec<Result<SentEmail, SendEmailError>>,
+    n_emails: usize,
+) -> Vec<Result<SentEmail, SendEmailError>> {
+    if outcomes.len() != n_emails {
+        tracing::error!(
+            n_outcomes = outcomes.len(),
+            n_emails,
+            "The email provider answered a batch with the wrong number of outcomes",
+        );
+        outcomes.resize_with(n_emails, || Err(SendEmailError::MissingOutcome));
+    }
+    outcomes
+}
+
+struct Provider {
+    name: String,
+    sender: Box<dyn EmailSender>,
+    circuit_breaker: CircuitBreaker,
+}
+
+impl Provider {
+    fn tag(&self, sent: SentEmail) -> SentEmail {
+        SentEmail {
+            provider: Some(self.name.clone()),
+            ..sent
+        }
+    }
+
+    async fn send_batch(
+        &self,
+        emails: &[OutgoingEmail<'_>],
+    ) -> Result<Vec<Result<SentEmail, SendEmailError>>, SendEmailError> {
+        if emails.len() <= self.sender.max_batch_size() {
+            return self.sender.send_batch(emails).await;
+        }
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for email in emails {
+            match self.sender.send(email).await {
+                // Nothing went out yet, so the whole batch can go to the next provider.
+                Err(e) if outcomes.is_empty() && e.kind() == SendEmailErrorKind::Transient => {
+                    return Err(e)
+                }
+                outcome => outcomes.push(outcome),
+            }
+        }
+        Ok(outcomes)
+    }
+}
+
+/// Sends through the first provider that is in rotation and moves on to the next one
+/// on transient errors. All of them share the same rate limits.
+pub struct EmailClient {
+    providers: Vec<Provider>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    domain_rate_limiter: DomainRateLimiter,
+    /// Set by a `429` that no fallback made up for, whether or not there is a rate limiter.
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl EmailClient {
+    pub fn new(name: impl Into<String>, sender: Box<dyn EmailSender>) -> Self {
+        Self {
+            providers: Vec::new(),
+            failure_threshold: u32::MAX,
+            cooldown: Duration::ZERO,
+            rate_limiter: None,
+            domain_rate_limiter: DomainRateLimiter::default(),
+            paused_until: Mutex::new(None),
+        }
+        .with_fallback(name, sender)
+    }
+
+    /// Adds a provider to try after the ones added before it.
+    pub fn with_fallback(mut self, name: impl Into<String>, sender: Box<dyn EmailSender>) -> Self {
+        self.providers.push(Provider {
+            name: name.into(),
+            sender,
+            circuit_breaker: CircuitBreaker::new(self.failure_threshold, self.cooldown),
+        });
+        self
+    }
+
+    /// Takes a provider out of rotation for `cooldown` after `failure_threshold`
+    /// transient failures in a row. Without it every send tries every provider.
+    pub fn with_circuit_breaker(mut self, failure_threshold: u32, cooldown: Duration) -> Self {
+        self.failure_threshold = failure_threshold;
+        self.cooldown = cooldown;
+        for provider in &mut self.providers {
+            provider.circuit_breaker = CircuitBreaker::new(failure_threshold, cooldown);
+        }
+        self
+    }
+
+    /// Holds every send back until the limiter allows it.
+    /// A `429` that no fallback made up for also empties the bucket of everyone sharing it.
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    pub fn with_domain_rate_limiter(mut self, domain_rate_limiter: DomainRateLimiter) -> Self {
+        self.domain_rate_limiter = domain_rate_limiter;
+        self
+    }
+
+    async fn acquire(&self, n: usize) {
+        loop {
+            let paused_until = *self.paused_until.lock().unwrap();
+            match paused_until {
+                Some(paused_until) if paused_until > Instant::now() => {
+                    tokio::time::sleep_until(paused_until).await
+                }
+                _ => break,
+            }
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire(n).await;
+        }
+    }
+
+    fn pause_if_rate_limited(&self, e: &SendEmailError) {
+        if let Some(retry_after) = e.retry_after() {
+            tracing::warn!(
+                retry_after_ms = retry_after.as_millis() as u64,
+                "The email provider is rate limiting us, pausing all sends",
+            );
+            let until = Instant::now() + retry_after;
+            let mut paused_until = self.paused_until.lock().unwrap();
+            *paused_until = Some(paused_until.map_or(until, |p| p.max(until)));
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.pause_for(retry_after);
+            }
+        }
+    }
+
+    /// Keeps the circuit of `provider` up to date and tells whether to try the next one.
+    fn should_fail_over<T>(
+        &self,
+        provider: &Provider,
+        outcome: &Result<T, SendEmailError>,
+    ) -> bool {
+        match outcome {
+            Err(e) if e.kind() == SendEmailErrorKind::Transient => {
+                tracing::warn!(
+                    provider = %provider.name,
+                    error.message = %e,
+                    "The email provider failed, trying the next one",
+                );
+                if provider.circuit_breaker.record_failure() {
+                    tracing::error!(
+                        provider = %provider.name,
+                        "The email provider keeps failing, taking it out of rotation",
+                    );
+                }
+                true
+            }
+            // It answered, even if it did not like the email: the provider itself is fine.
+            _ => {
+                if provider.circuit_breaker.record_success() {
+                    tracing::info!(
+                        provider = %provider.name,
+                        "The email provider recovered, putting it back in rotation",
+                    );
+                }
+                false
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for EmailClient {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<SentEmail, SendEmailError> {
+        self.acquire(1).await;
+        let mut outcome = Err(SendEmailError::NoProviderAvailable);
+        for provider in &self.providers {
+            if !provider.circuit_breaker.try_acquire() {
+                continue;
+            }
+            outcome = provider
+                .sender
+                .send(email)
+                .await
+                .map(|sent| provider.tag(sent));
+            if !self.should_fail_over(provider, &outcome) {
+                break;
+            }
+        }
+        if let Err(e) = &outcome {
+            self.pause_if_rate_limited(e);
+        }
+        outcome
+    }
+
+    async fn send_batch(
+        &self,
+        emails: &[OutgoingEmail<'_>],
+    ) -> Result<Vec<Result<SentEmail, SendEmailError>>, SendEmailError> {
+        self.acquire(emails.len()).await;
+        let mut outcome = Err(SendEmailError::NoProviderAvailable);
+        for provider in &self.providers {
+            if !provider.circuit_breaker.try_acquire() {
+                continue;
+            }
+            outcome = provider.send_batch(emails).await.map(|outcomes| {
+                outcomes
+                    .into_iter()
+                    .map(|outcome| outcome.map(|sent| provider.tag(sent)))
+                    .collect()
+            });
+            if !self.should_fail_over(provider, &outcome) {
+                break;
+            }
+        }
+        if let Err(e) = &outcome {
+            self.pause_if_rate_limited(e);
+        }
+        outcome
+    }
+
+    /// Batches are sized for the primary provider; fallbacks that take fewer emails per call
+    /// send them one by one.
+    fn max_batch_size(&self) -> usize {
+        self.providers[0].sender.max_batch_size()
+    }
+
+    fn try_reserve_for(&self, recipient: &str) -> Result<(), Duration> {
+        self.domain_rate_limiter.try_acquire(recipient)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domains::SubscriberEmail;
+    use crate::email_client::{
+        EmailClient, EmailSender, OutgoingEmail, PostmarkEmailSender, SendEmailErrorKind,
+        StdoutEmailSender,
+    };
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use std::time::Duration;
+    use wiremock::matchers::any;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+    }
+
+    fn postmark(server: &MockServer) -> Box<PostmarkEmailSender> {
+        Box::new(PostmarkEmailSender::new(
+            server.uri(),
+            email(),
+            Secret::new("token".into()),
+            Duration::from_millis(200),
+        ))
+    }
+
+    async fn send(client: &EmailClient) -> Result<super::SentEmail, super::SendEmailError> {
+        client
+            .send_email(&email(), "Subject", "<p>Body</p>", "Body")
+            .await
+    }
+
+    #[tokio::test]
+    async fn a_transient_failure_fails_over_to_the_next_provider() {
+        let primary = MockServer::start().await;
+        let backup = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&backup)
+            .await;
+        let client = EmailClient::new("primary", postmark(&primary))
+            .with_fallback("backup", postmark(&backup));
+
+        let sent = assert_ok!(send(&client).await);
+
+        assert_eq!(sent.provider.as_deref(), Some("backup"));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_recipient_does_not_fail_over() {
+        let primary = MockServer::start().await;
+        let backup = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                "ErrorCode": 406,
+                "Message": "You tried to send to a recipient that has been marked as inactive."
+            })))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&backup)
+            .await;
+        let client = EmailClient::new("primary", postmark(&primary))
+            .with_fallback("backup", postmark(&backup));
+
+        let outcome = send(&client).await;
+
+        assert_eq!(
+            assert_err!(outcome).kind(),
+            SendEmailErrorKind::PermanentRecipient
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failing_provider_is_skipped_until_it_recovers() {
+        let primary = MockServer::start().await;
+        let backup = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&backup)
+            .await;
+        let client = EmailClient::new("primary", postmark(&primary))
+            .with_fallback("backup", postmark(&backup))
+            .with_circuit_breaker(1, Duration::from_millis(200));
+
+        // The first failure takes the primary out of rotation...
+        assert_eq!(
+            send(&client).await.unwrap().provider.as_deref(),
+            Some("backup")
+        );
+        assert_eq!(
+            send(&client).await.unwrap().provider.as_deref(),
+            Some("backup")
+        );
+        // ...until the cooldown is over and it gets another chance.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(
+            send(&client).await.unwrap().provider.as_deref(),
+            Some("primary")
+        );
+    }
+
+    #[tokio::test]
+    async fn every_provider_out_of_rotation_is_a_transient_error() {
+        let primary = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        let client = EmailClient::new("primary", postmark(&primary))
+            .with_circuit_breaker(1, Duration::from_secs(30));
+
+        assert_err!(send(&client).await);
+        let outcome = send(&client).await;
+
+        assert_eq!(assert_err!(outcome).kind(), SendEmailErrorKind::Transient);
+    }
+
+    #[tokio::test]
+    async fn a_batch_fails_over_to_a_provider_without_a_batch_api() {
+        let primary = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        let client = EmailClient::new("primary", postmark(&primary))
+            .with_fallback("local", Box::new(StdoutEmailSender));
+        let recipient = email();
+        let messages: Vec<_> = (0..2)
+            .map(|_| OutgoingEmail {
+                recipient: &recipient,
+                subject: "Subject",
+                html_content: "<p>Body</p>",
+                text_content: "Body",
+                headers: &[],
+                idempotency_key: None,
+            })
+            .collect();
+
+        let outcomes = assert_ok!(client.send_batch(&messages).await);
+
+        assert_eq!(outcomes.len(), 2);
+        for outcome in &outcomes {
+            assert_eq!(assert_ok!(outcome).provider.as_deref(), Some("local"));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_429_pauses_every_send_even_without_a_rate_limiter() {
+        let primary = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        let client = EmailClient::new("primary", postmark(&primary));
+
+        let outcome = send(&client).await;
+        assert_eq!(assert_err!(outcome).kind(), SendEmailErrorKind::Transient);
+
+        let start = std::time::Instant::now();
+        assert_ok!(send(&client).await);
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+}
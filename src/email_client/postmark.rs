@@ -0,0 +1,617 @@
+use super::{
+    message_id, one_outcome_per_email, EmailHeader, EmailSender, OutgoingEmail, PostmarkError,
+    SendEmailError, SendEmailErrorKind, SentEmail,
+};
+use crate::domains::SubscriberEmail;
+use reqwest::{header::RETRY_AFTER, Client, StatusCode};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Sends through Postmark's JSON API.
+pub struct PostmarkEmailSender {
+    client: Client,
+    base_url: String,
+    sender_email: SubscriberEmail,
+    authorization_token: Secret<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<EmailHeader>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata<'a>>,
+}
+
+// Postmark keeps metadata with the message and returns it in its API and webhooks.
+#[derive(Serialize, Debug)]
+struct Metadata<'a> {
+    idempotency_key: &'a str,
+}
+
+// Postmark answers a batch with one entry per message, in the order they were sent.
+// A zero `ErrorCode` means the message was accepted.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResult {
+    error_code: i64,
+    message: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SendEmailResponse {
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+/// Postmark accepts at most this many messages per batch call.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+/// How long to back off after a `429` that does not say when to come back.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// https://postmarkapp.com/developer/api/overview#error-codes
+const POSTMARK_INACTIVE_RECIPIENT: i64 = 406;
+
+/// Only a handful of codes are about our account or server token; the rest are about
+/// the email at hand and must not hold up the others.
+pub(super) fn error_kind(error_code: i64) -> SendEmailErrorKind {
+    match error_code {
+        POSTMARK_INACTIVE_RECIPIENT => SendEmailErrorKind::PermanentRecipient,
+        // Bad token, unknown or unconfirmed sender signature, account not allowed to send,
+        // batch too large, account pending approval, trial restrictions.
+        10 | 400 | 401 | 405 | 410 | 412 | 413 => SendEmailErrorKind::Configuration,
+        _ => SendEmailErrorKind::PermanentMessage,
+    }
+}
+
+impl PostmarkEmailSender {
+    pub fn new(
+        base_url: String,
+        sender_email: SubscriberEmail,
+        authorization_token: Secret<String>,
+        timeout_milliseconds: Duration,
+    ) -> Self {
+        Self {
+            client: Client::builder()
+                .timeout(timeout_milliseconds)
+                .build()
+                .unwrap(),
+            base_url,
+            sender_email,
+            authorization_token,
+        }
+    }
+
+    fn request<'a>(&'a self, email: &'a OutgoingEmail<'_>) -> SendEmailRequest<'a> {
+        let mut headers = email.headers.to_vec();
+        if let Some(key) = email.idempotency_key {
+            headers.push(EmailHeader::new(
+                "Message-ID",
+                message_id(key, &self.sender_email),
+            ));
+        }
+        SendEmailRequest {
+            to: email.recipient.as_ref(),
+            from: self.sender_email.as_ref(),
+            subject: email.subject,
+            html_body: email.html_content,
+            text_body: email.text_content,
+            headers,
+            metadata: email
+                .idempotency_key
+                .map(|idempotency_key| Metadata { idempotency_key }),
+        }
+    }
+
+    async fn post(
+        &self,
+        url: String,
+        body: &impl Serialize,
+    ) -> Result<reqwest::Response, SendEmailError> {
+        let response = self
+            .client
+            .post(url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(body)
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let retry_after = (status == StatusCode::TOO_MANY_REQUESTS)
+            .then(|| retry_after(&response).unwrap_or(DEFAULT_RETRY_AFTER));
+        let body = response.text().await?;
+        Err(SendEmailError::Rejected {
+            status,
+            error: serde_json::from_str(&body).ok(),
+            body,
+            retry_after,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for PostmarkEmailSender {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<SentEmail, SendEmailError> {
+        let url = format!("{}/email", self.base_url);
+        let response = self.post(url, &self.request(email)).await?;
+        // The email is out either way, so a body we cannot read only costs us the message id.
+        let message_id = response
+            .json::<SendEmailResponse>()
+            .await
+            .ok()
+            .and_then(|r| r.message_id);
+        Ok(SentEmail {
+            message_id,
+            provider: None,
+        })
+    }
+
+    async fn send_batch(
+        &self,
+        emails: &[OutgoingEmail<'_>],
+    ) -> Result<Vec<Result<SentEmail, SendEmailError>>, SendEmailError> {
+        assert!(
+            emails.len() <= MAX_BATCH_SIZE,
+            "Postmark accepts at most {MAX_BATCH_SIZE} emails per batch"
+        );
+        let url = format!("{}/email/batch", self.base_url);
+        let request_body: Vec<_> = emails.iter().map(|email| self.request(email)).collect();
+        let response = self.post(url, &request_body).await?;
+        // Postmark took the batch, so the emails are out either way. A body we cannot read
+        // only costs us their message ids; it must not get them sent again.
+        let results: Vec<BatchResult> = match response.json().await {
+            Ok(results) => results,
+            Err(e) => {
+                tracing::error!(
+                    error.message = %e,
+                    "Failed to read the answer to an accepted batch, assuming every email went out",
+                );
+                return Ok(emails.iter().map(|_| Ok(SentEmail::default())).collect());
+            }
+        };
+        let outcomes = results
+            .into_iter()
+            .map(|result| match result.error_code {
+                0 => Ok(SentEmail {
+                    message_id: result.message_id,
+                    provider: None,
+                }),
+                error_code => Err(SendEmailError::MessageRejected(PostmarkError {
+                    error_code,
+                    message: result.message,
+                })),
+            })
+            .collect();
+        Ok(one_outcome_per_email(outcomes, emails.len()))
+    }
+
+    fn max_batch_size(&self) -> usize {
+        MAX_BATCH_SIZE
+    }
+}
+
+/// Reads `Retry-After`, which is either a number of seconds or an HTTP date.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::domains::SubscriberEmail;
+    use crate::email_client::{
+        EmailClient, EmailHeader, EmailSender, OutgoingEmail, PostmarkEmailSender,
+        SendEmailErrorKind,
+    };
+    use crate::rate_limiter::RateLimiter;
+    use claims::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use secrecy::Secret;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use wiremock::matchers::{any, body_partial_json, header, header_exists, method, path};
+    use wiremock::{Match, Mock, MockServer, Request, ResponseTemplate};
+
+    struct SendEmailRequestBodyMatcher;
+    impl Match for SendEmailRequestBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    fn paragraph() -> String {
+        Paragraph(1..2).fake()
+    }
+
+    fn email_client(base_url: String) -> PostmarkEmailSender {
+        PostmarkEmailSender::new(
+            base_url,
+            email(),
+            Secret::new(Faker.fake()),
+            Duration::from_millis(100),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request_to_base_url() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailRequestBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_returns_the_provider_message_id() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "To": "receiver@example.com",
+                "SubmittedAt": "2014-02-17T07:25:01.4178645-05:00",
+                "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+                "ErrorCode": 0,
+                "Message": "OK"
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+
+        assert_eq!(
+            assert_ok!(outcome).message_id.as_deref(),
+            Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_with_headers_forwards_custom_headers() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "Headers": [{ "Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click" }]
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let headers = [EmailHeader::new(
+            "List-Unsubscribe-Post",
+            "List-Unsubscribe=One-Click",
+        )];
+        let outcome = email_client
+            .send_email_with_headers(&email(), &subject(), &paragraph(), &paragraph(), &headers)
+            .await;
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_server_responds_500() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn test_if_server_takes_too_long() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500).set_delay(Duration::from_secs(180)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn inactive_recipients_are_a_permanent_recipient_error() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                "ErrorCode": 406,
+                "Message": "You tried to send to a recipient that has been marked as inactive."
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+        assert_eq!(
+            assert_err!(outcome).kind(),
+            SendEmailErrorKind::PermanentRecipient
+        );
+    }
+
+    #[tokio::test]
+    async fn an_invalid_email_is_refused_on_its_own() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                "ErrorCode": 300,
+                "Message": "Invalid email request"
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+        assert_eq!(
+            assert_err!(outcome).kind(),
+            SendEmailErrorKind::PermanentMessage
+        );
+    }
+
+    #[tokio::test]
+    async fn an_invalid_server_token_is_a_configuration_error() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(401).set_body_json(serde_json::json!({
+                "ErrorCode": 10,
+                "Message": "Bad or missing Server API token."
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+        assert_eq!(
+            assert_err!(outcome).kind(),
+            SendEmailErrorKind::Configuration
+        );
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_timeouts_are_transient() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .mount(&mock_server)
+            .await;
+
+        for _ in 0..2 {
+            let outcome = email_client
+                .send_email(&email(), &subject(), &paragraph(), &paragraph())
+                .await;
+            assert_eq!(assert_err!(outcome).kind(), SendEmailErrorKind::Transient);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_the_outcome_of_each_email() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                { "ErrorCode": 0, "Message": "OK", "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817" },
+                { "ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive." }
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let (first, second) = (email(), email());
+        let (subject, content) = (subject(), paragraph());
+        let emails: Vec<_> = [&first, &second]
+            .into_iter()
+            .map(|recipient| OutgoingEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                headers: &[],
+                idempotency_key: None,
+            })
+            .collect();
+        let outcome = assert_ok!(email_client.send_batch(&emails).await);
+
+        assert_eq!(outcome.len(), 2);
+        assert_eq!(
+            assert_ok!(&outcome[0]).message_id.as_deref(),
+            Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+        );
+        assert_eq!(
+            assert_err!(&outcome[1]).kind(),
+            SendEmailErrorKind::PermanentRecipient
+        );
+    }
+
+    #[tokio::test]
+    async fn emails_missing_from_a_batch_response_are_transient_failures() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                { "ErrorCode": 0, "Message": "OK" }
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let (first, second) = (email(), email());
+        let (subject, content) = (subject(), paragraph());
+        let emails: Vec<_> = [&first, &second]
+            .into_iter()
+            .map(|recipient| OutgoingEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                headers: &[],
+                idempotency_key: None,
+            })
+            .collect();
+        let outcome = assert_ok!(email_client.send_batch(&emails).await);
+
+        assert_eq!(outcome.len(), 2);
+        assert_ok!(&outcome[0]);
+        assert_eq!(
+            assert_err!(&outcome[1]).kind(),
+            SendEmailErrorKind::Transient
+        );
+    }
+
+    #[tokio::test]
+    async fn an_accepted_batch_with_an_unreadable_body_counts_as_sent() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("not json"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let (first, second) = (email(), email());
+        let (subject, content) = (subject(), paragraph());
+        let emails: Vec<_> = [&first, &second]
+            .into_iter()
+            .map(|recipient| OutgoingEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                headers: &[],
+                idempotency_key: None,
+            })
+            .collect();
+        let outcome = assert_ok!(email_client.send_batch(&emails).await);
+
+        assert_eq!(outcome.len(), 2);
+        for outcome in &outcome {
+            assert_eq!(assert_ok!(outcome).message_id, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_429_pauses_every_send_for_the_retry_after_period() {
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new("postmark", Box::new(email_client(mock_server.uri())))
+            .with_rate_limiter(Arc::new(RateLimiter::new(100.0, 100)));
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+        assert_eq!(assert_err!(outcome).kind(), SendEmailErrorKind::Transient);
+
+        let start = std::time::Instant::now();
+        let outcome = email_client
+            .send_email(&email(), &subject(), &paragraph(), &paragraph())
+            .await;
+        assert_ok!(outcome);
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,247 @@
+use super::{
+    message_id, EmailSender, OutgoingEmail, SendEmailError, SendEmailErrorKind, SentEmail,
+};
+use crate::domains::SubscriberEmail;
+use anyhow::Context;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use secrecy::{ExposeSecret, Secret};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How the connection to the SMTP server is secured.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain connection upgraded with `STARTTLS`, usually on port 587.
+    StartTls,
+    /// TLS from the first byte, usually on port 465.
+    Tls,
+    /// No encryption at all. Only meant for a server on the same machine.
+    None,
+}
+
+/// Sends through any SMTP server.
+pub struct SmtpEmailSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    sender_email: SubscriberEmail,
+}
+
+impl SmtpEmailSender {
+    pub fn new(
+        host: &str,
+        port: u16,
+        tls: SmtpTls,
+        credentials: Option<(String, Secret<String>)>,
+        sender_email: SubscriberEmail,
+        timeout: Duration,
+    ) -> Result<Self, lettre::transport::smtp::Error> {
+        let builder = match tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        };
+        let mut builder = builder.port(port).timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_owned(),
+            ));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            sender_email,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SmtpEmailSender {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<SentEmail, SendEmailError> {
+        let (message, message_id) = build_message(&self.sender_email, email)?;
+        self.transport.send(message).await?;
+        Ok(SentEmail {
+            message_id: Some(message_id),
+            provider: None,
+        })
+    }
+}
+
+/// Builds the MIME message for backends that deal in raw emails and returns it with its `Message-ID`.
+pub(super) fn build_message(
+    sender_email: &SubscriberEmail,
+    email: &OutgoingEmail<'_>,
+) -> Result<(Message, String), SendEmailError> {
+    let id = match email.idempotency_key {
+        Some(key) => message_id(key, sender_email),
+        None => message_id(&Uuid::new_v4().to_string(), sender_email),
+    };
+    let from: Mailbox = sender_email
+        .as_ref()
+        .parse()
+        .context("Invalid sender address")
+        .map_err(SendEmailError::Message)?;
+    let to: Mailbox = email
+        .recipient
+        .as_ref()
+        .parse()
+        .context("Invalid recipient address")
+        .map_err(SendEmailError::InvalidRecipient)?;
+    let mut builder = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(email.subject)
+        .message_id(Some(id.clone()));
+    for header in email.headers {
+        let name = HeaderName::new_from_ascii(header.name.clone())
+            .context("Invalid header name")
+            .map_err(SendEmailError::Message)?;
+        builder = builder.raw_header(HeaderValue::new(name, header.value.clone()));
+    }
+    let message = builder
+        .multipart(MultiPart::alternative_plain_html(
+            email.text_content.to_owned(),
+            email.html_content.to_owned(),
+        ))
+        .context("Failed to assemble the email")
+        .map_err(SendEmailError::Message)?;
+    Ok((message, id))
+}
+
+// https://www.rfc-editor.org/rfc/rfc5321#section-4.2.3
+pub(super) fn error_kind(e: &lettre::transport::smtp::Error) -> SendEmailErrorKind {
+    match e.status().map(u16::from) {
+        // Mailbox unavailable, user not local, mailbox name not allowed,
+        // domain does not accept mail.
+        Some(550 | 551 | 553 | 521 | 556) => SendEmailErrorKind::PermanentRecipient,
+        // We speak SMTP wrong, or cannot log in.
+        Some(500..=504 | 555 | 530 | 534 | 535 | 538) => SendEmailErrorKind::Configuration,
+        // Mailbox full, message too big, rejected by policy...: this email, to this recipient.
+        Some(_) if e.is_permanent() => SendEmailErrorKind::PermanentMessage,
+        Some(_) => SendEmailErrorKind::Transient,
+        // We could not even talk to the server properly.
+        None if e.is_client() || e.is_tls() => SendEmailErrorKind::Configuration,
+        None => SendEmailErrorKind::Transient,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SmtpEmailSender, SmtpTls};
+    use crate::domains::SubscriberEmail;
+    use crate::email_client::{EmailSender, OutgoingEmail, SendEmailErrorKind};
+    use claims::{assert_err, assert_ok};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    /// A bare-bones SMTP server that accepts a single email, or rejects every recipient
+    /// with `rcpt_reply`, and hands over what it received.
+    async fn smtp_stand_in(rcpt_reply: &'static str) -> (u16, oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut data = String::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply = match line.split_whitespace().next().unwrap_or_default() {
+                    "EHLO" | "HELO" => "250 localhost\r\n",
+                    "MAIL" => "250 OK\r\n",
+                    "RCPT" => rcpt_reply,
+                    "DATA" => {
+                        writer.write_all(b"354 Go ahead\r\n").await.unwrap();
+                        while let Some(line) = lines.next_line().await.unwrap() {
+                            if line == "." {
+                                break;
+                            }
+                            data.push_str(&line);
+                            data.push('\n');
+                        }
+                        "250 Queued\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 Bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => "250 OK\r\n",
+                };
+                writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+            let _ = sender.send(data);
+        });
+        (port, receiver)
+    }
+
+    fn sender(port: u16) -> SmtpEmailSender {
+        SmtpEmailSender::new(
+            "127.0.0.1",
+            port,
+            SmtpTls::None,
+            None,
+            SubscriberEmail::parse("newsletter@example.com".into()).unwrap(),
+            Duration::from_secs(2),
+        )
+        .unwrap()
+    }
+
+    fn email(recipient: &SubscriberEmail) -> OutgoingEmail<'_> {
+        OutgoingEmail {
+            recipient,
+            subject: "Our latest issue",
+            html_content: "<p>Hello</p>",
+            text_content: "Hello",
+            headers: &[],
+            idempotency_key: Some("3f6d2c4e-4a57-4c4b-9a53-8d2f6f1b6c1e"),
+        }
+    }
+
+    #[tokio::test]
+    async fn emails_are_handed_to_the_smtp_server() {
+        let (port, received) = smtp_stand_in("250 OK\r\n").await;
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+
+        let sent = assert_ok!(sender(port).send(&email(&recipient)).await);
+
+        let data = received.await.unwrap();
+        assert!(data.contains("Subject: Our latest issue"));
+        assert!(data.contains("To: ursula@example.com"));
+        assert!(data.contains("Message-ID: <3f6d2c4e-4a57-4c4b-9a53-8d2f6f1b6c1e@example.com>"));
+        assert_eq!(
+            sent.message_id.as_deref(),
+            Some("<3f6d2c4e-4a57-4c4b-9a53-8d2f6f1b6c1e@example.com>")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_policy_rejection_only_concerns_that_email() {
+        let (port, _received) = smtp_stand_in("554 5.7.1 Message rejected\r\n").await;
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+
+        let outcome = sender(port).send(&email(&recipient)).await;
+
+        assert_eq!(
+            assert_err!(outcome).kind(),
+            SendEmailErrorKind::PermanentMessage
+        );
+    }
+
+    #[tokio::test]
+    async fn an_unknown_mailbox_is_a_permanent_recipient_error() {
+        let (port, _received) = smtp_stand_in("550 5.1.1 No such user\r\n").await;
+        let recipient = SubscriberEmail::parse("nobody@example.com".into()).unwrap();
+
+        let outcome = sender(port).send(&email(&recipient)).await;
+
+        assert_eq!(
+            assert_err!(outcome).kind(),
+            SendEmailErrorKind::PermanentRecipient
+        );
+    }
+}
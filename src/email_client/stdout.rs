@@ -0,0 +1,17 @@
+use super::{EmailSender, OutgoingEmail, SendEmailError, SentEmail};
+
+/// Logs every email instead of sending it, for local development.
+pub struct StdoutEmailSender;
+
+#[async_trait::async_trait]
+impl EmailSender for StdoutEmailSender {
+    async fn send(&self, email: &OutgoingEmail<'_>) -> Result<SentEmail, SendEmailError> {
+        tracing::info!(
+            recipient = %email.recipient.as_ref(),
+            subject = email.subject,
+            text_content = email.text_content,
+            "Not sending an email, logging it instead",
+        );
+        Ok(SentEmail::default())
+    }
+}
@@ -0,0 +1,328 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::issue_delivery_worker::{
+    is_configuration_error, listen_for_new_tasks, retry_delay, suppress_subscriber,
+    ExecutionOutcome,
+};
+use crate::{
+    configuration::{DeliverySettings, Settings, WorkerSettings},
+    shutdown::Shutdown,
+    startup::get_connection_pool,
+    suppressions,
+};
+use crate::{
+    domains::SubscriberEmail,
+    email_client::{EmailSender, SendEmailErrorKind},
+};
+use chrono::Utc;
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use tokio::sync::watch;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+struct OutboxEmail {
+    id: Uuid,
+    recipient: String,
+    subject: String,
+    html_content: String,
+    text_content: String,
+    n_retries: i16,
+}
+
+/// Channel `enqueue_email` notifies, so confirmation emails go out as soon as they are queued.
+pub const OUTBOX_CHANNEL: &str = "email_outbox";
+
+/// Queues the email and wakes the outbox worker once `transaction` commits.
+#[tracing::instrument(skip_all)]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_content: &str,
+    text_content: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_outbox (id, recipient, subject, html_content, text_content)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        Uuid::new_v4(),
+        recipient.as_ref(),
+        subject,
+        html_content,
+        text_content
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query("SELECT pg_notify($1, '')")
+        .bind(OUTBOX_CHANNEL)
+        .execute(transaction)
+        .await?;
+    Ok(())
+}
+
+/// Leases the next due email to `worker_id`. Like delivery tasks, the claim is committed
+/// straight away, so no connection is held while the email is sent.
+#[tracing::instrument(skip(pool))]
+async fn claim_email(
+    pool: &PgPool,
+    worker_id: &str,
+    lease_duration: Duration,
+) -> Result<Option<OutboxEmail>, anyhow::Error> {
+    let leased_until = Utc::now() + chrono::Duration::from_std(lease_duration)?;
+    let email = sqlx::query_as!(
+        OutboxEmail,
+        r#"
+        UPDATE email_outbox
+        SET leased_until = $2, leased_by = $1
+        WHERE id = (
+            SELECT id
+            FROM email_outbox
+            WHERE execute_after <= now() AND (leased_until IS NULL OR leased_until < now())
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        RETURNING id, recipient, subject, html_content, text_content, n_retries
+        "#,
+        worker_id,
+        leased_until
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(email)
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_email<'c>(
+    executor: impl PgExecutor<'c>,
+    id: Uuid,
+    worker_id: &str,
+) -> Result<(), anyhow::Error> {
+    let result = sqlx::query!(
+        r#"DELETE FROM email_outbox WHERE id = $1 AND leased_by = $2"#,
+        id,
+        worker_id
+    )
+    .execute(executor)
+    .await?;
+    // Another worker reclaimed the email after our lease ran out; it owns the outcome now.
+    if result.rows_affected() == 0 {
+        tracing::warn!("Lost the lease on an outbox email before recording its outcome");
+    }
+    Ok(())
+}
+
+/// Gives the email back to the outbox without counting an attempt.
+#[tracing::instrument(skip_all)]
+async fn release_email(pool: &PgPool, id: Uuid, worker_id: &str) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET leased_until = NULL, leased_by = NULL
+        WHERE id = $1 AND leased_by = $2
+        "#,
+        id,
+        worker_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    pool: &PgPool,
+    id: Uuid,
+    worker_id: &str,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    let execute_after = Utc::now() + chrono::Duration::from_std(delay)?;
+    sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET
+            n_retries = n_retries + 1,
+            execute_after = $3,
+            leased_until = NULL,
+            leased_by = NULL
+        WHERE id = $1 AND leased_by = $2
+        "#,
+        id,
+        worker_id,
+        execute_after
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    skip(pool, email_client, delivery),
+    fields(outbox_email_id=tracing::field::Empty, recipient=tracing::field::Empty)
+)]
+pub async fn try_execute_outbox_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    delivery: &DeliverySettings,
+    worker_id: &str,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let email = match claim_email(pool, worker_id, delivery.lease_duration()).await? {
+        Some(email) => email,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current()
+        .record("outbox_email_id", display(email.id))
+        .record("recipient", display(&email.recipient));
+
+    let recipient = match SubscriberEmail::parse(email.recipient.clone()) {
+        Ok(recipient) => recipient,
+        Err(e) => {
+            tracing::error!(
+                error.message = %e,
+                "Dropping an outbox email. The recipient address is invalid",
+            );
+            delete_email(pool, email.id, worker_id).await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    };
+
+    if suppressions::is_suppressed(pool, recipient.as_ref()).await? {
+        tracing::warn!("Dropping an outbox email. The recipient is on the suppression list");
+        delete_email(pool, email.id, worker_id).await?;
+        return Ok(ExecutionOutcome::TaskCompleted);
+    }
+
+    match email_client
+        .send_email(
+            &recipient,
+            &email.subject,
+            &email.html_content,
+            &email.text_content,
+        )
+        .await
+    {
+        Ok(_) => delete_email(pool, email.id, worker_id).await?,
+        // Leave the email in the outbox: it will go out once the setup is fixed.
+        Err(e) if e.kind() == SendEmailErrorKind::Configuration => {
+            release_email(pool, email.id, worker_id).await?;
+            return Err(
+                anyhow::Error::new(e).context("The email provider rejected our configuration")
+            );
+        }
+        Err(e) if e.kind() == SendEmailErrorKind::PermanentRecipient => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "The email provider will not deliver to this recipient. Suppressing the address",
+            );
+            let mut transaction = pool.begin().await?;
+            suppress_subscriber(&mut transaction, recipient.as_ref()).await?;
+            delete_email(&mut transaction, email.id, worker_id).await?;
+            transaction.commit().await?;
+        }
+        Err(e) if e.kind() == SendEmailErrorKind::PermanentMessage => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "The email provider refused an outbox email. Dropping it",
+            );
+            delete_email(pool, email.id, worker_id).await?;
+        }
+        Err(e) if email.n_retries + 1 >= delivery.max_attempts => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to deliver an outbox email. Giving up after {} attempts",
+                delivery.max_attempts
+            );
+            delete_email(pool, email.id, worker_id).await?;
+        }
+        Err(e) => {
+            let delay = retry_delay(delivery.retry_base_delay(), email.n_retries);
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                retry_in_ms = delay.as_millis() as u64,
+                "Failed to deliver an outbox email. Scheduling a retry",
+            );
+            schedule_retry(pool, email.id, worker_id, delay).await?;
+        }
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+async fn worker_loop(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    delivery: DeliverySettings,
+    worker: WorkerSettings,
+    worker_id: String,
+    mut wake: watch::Receiver<()>,
+    mut shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
+    while !shutdown.is_requested() {
+        // Anything notified from here on may not be visible to the claim below, so it must wake us again.
+        wake.borrow_and_update();
+        match try_execute_outbox_task(&pool, email_client.as_ref(), &delivery, &worker_id).await {
+            // Polling is only a fallback for notifications that got lost.
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::select! {
+                    _ = wake.changed() => {}
+                    _ = shutdown.sleep(worker.idle_poll_interval()) => {}
+                }
+            }
+            Err(e) if is_configuration_error(&e) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Stopping the outbox worker until the email provider setup is fixed",
+                );
+                return Err(e);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to execute an outbox task",
+                );
+                shutdown.sleep(worker.error_backoff()).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Sends queued emails, woken through `OUTBOX_CHANNEL` as soon as one is enqueued.
+/// `email_client` is shared with the delivery worker, so that both draw from the same rate limits.
+pub async fn run_outbox_worker_until_stopped(
+    configuration: Settings,
+    email_client: Arc<dyn EmailSender>,
+    shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration);
+    let (wake, wake_receiver) = watch::channel(());
+    let listener = listen_for_new_tasks(
+        connection_pool.clone(),
+        OUTBOX_CHANNEL,
+        wake,
+        configuration.worker.error_backoff(),
+    );
+    let worker = worker_loop(
+        connection_pool,
+        email_client,
+        configuration.delivery,
+        configuration.worker,
+        format!("outbox-worker-{}", Uuid::new_v4()),
+        wake_receiver,
+        shutdown,
+    );
+    // The listener never returns on its own, so this ends with the worker.
+    tokio::select! {
+        outcome = worker => outcome,
+        outcome = listener => outcome,
+    }
+}
@@ -1,11 +1,31 @@
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::{configuration::Settings, startup::get_connection_pool};
+use crate::{
+    configuration::{DeliverySettings, Settings, WorkerSettings},
+    startup::get_connection_pool,
+};
 use crate::{
     domains::SubscriberEmail,
-    email_client::{self, EmailClient},
+    email_client::{
+        self, EmailHeader, EmailSender, OutgoingEmail, SendEmailError, SendEmailErrorKind,
+        SentEmail,
+    },
+    routes::unsubscribe_link,
+    shutdown::{shutdown_channel, Shutdown},
+    suppressions,
+    tracking::{add_open_pixel, click_url, open_pixel_url, rewrite_links},
 };
-use sqlx::{PgPool, Postgres, Transaction};
+use chrono::Utc;
+use rand::Rng;
+use secrecy::Secret;
+use sqlx::postgres::PgListener;
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tracing::{field::display, Span};
 use uuid::Uuid;
 
@@ -13,6 +33,21 @@ struct NewsLetterIssue {
     title: String,
     text_content: String,
     html_content: String,
+    track_opens: bool,
+    track_clicks: bool,
+}
+
+/// Channel producers of delivery tasks `NOTIFY` so idle workers pick the tasks up straight away.
+pub const DELIVERY_QUEUE_CHANNEL: &str = "issue_delivery_queue";
+
+/// Wakes the delivery workers. When called inside a transaction,
+/// Postgres only sends the notification once the transaction commits.
+pub async fn notify_delivery_workers<'c>(executor: impl PgExecutor<'c>) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_notify($1, '')")
+        .bind(DELIVERY_QUEUE_CHANNEL)
+        .execute(executor)
+        .await?;
+    Ok(())
 }
 
 pub enum ExecutionOutcome {
@@ -35,60 +70,320 @@ pub enum ExecutionOutcome {
 
 type PgTransaction = Transaction<'static, Postgres>;
 
-#[tracing::instrument(skip_all)]
-async fn dequeue_task(
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_retries: i16,
+}
+
+/// Leases up to `limit` due tasks, all for the same issue, to `worker_id`.
+/// The claim is committed straight away, so no connection is held while emails are sent;
+/// a lease that runs out (e.g. because its worker crashed) makes the task claimable again.
+#[tracing::instrument(skip(pool))]
+async fn claim_tasks(
     pool: &PgPool,
-) -> Result<Option<(PgTransaction, Uuid, String)>, anyhow::Error> {
-    let mut transaction = pool.begin().await?;
-    let r = sqlx::query!(
+    worker_id: &str,
+    lease_duration: Duration,
+    limit: i64,
+) -> Result<Vec<DeliveryTask>, anyhow::Error> {
+    let leased_until = Utc::now() + chrono::Duration::from_std(lease_duration)?;
+    // The issue row locked by the CTE is ours, so SKIP LOCKED keeps it in the batch.
+    let tasks = sqlx::query_as!(
+        DeliveryTask,
         r#"
-        SELECT newsletter_issue_id, subscriber_email
-        FROM issue_delivery_queue
-        FOR UPDATE
-        SKIP LOCKED
-        LIMIT 1
-        "#
+        WITH issue AS (
+            SELECT newsletter_issue_id
+            FROM issue_delivery_queue
+            WHERE execute_after <= now() AND (leased_until IS NULL OR leased_until < now())
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        UPDATE issue_delivery_queue
+        SET leased_until = $2, leased_by = $1
+        WHERE (newsletter_issue_id, subscriber_email) IN (
+            SELECT newsletter_issue_id, subscriber_email
+            FROM issue_delivery_queue
+            WHERE
+                newsletter_issue_id = (SELECT newsletter_issue_id FROM issue) AND
+                execute_after <= now() AND
+                (leased_until IS NULL OR leased_until < now())
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT $3
+        )
+        RETURNING newsletter_issue_id, subscriber_email, n_retries
+        "#,
+        worker_id,
+        leased_until,
+        limit
     )
-    .fetch_optional(&mut transaction)
+    .fetch_all(pool)
     .await?;
 
-    if let Some(r) = r {
-        Ok(Some((
-            transaction,
-            r.newsletter_issue_id,
-            r.subscriber_email,
-        )))
-    } else {
-        Ok(None)
+    Ok(tasks)
+}
+
+/// Pushes back the end of every lease `worker_id` holds.
+#[tracing::instrument(skip(pool))]
+async fn renew_leases(
+    pool: &PgPool,
+    worker_id: &str,
+    lease_duration: Duration,
+) -> Result<(), anyhow::Error> {
+    let leased_until = Utc::now() + chrono::Duration::from_std(lease_duration)?;
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue SET leased_until = $2 WHERE leased_by = $1"#,
+        worker_id,
+        leased_until
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Keeps renewing the leases of `worker_id` until `send` is done. Rate limits, 429 pauses
+/// and fallbacks that send one email at a time can all outlast a lease, and another worker
+/// would then claim the tasks and send them again.
+async fn renewing_leases<T>(
+    pool: &PgPool,
+    worker_id: &str,
+    lease_duration: Duration,
+    send: impl Future<Output = T>,
+) -> T {
+    tokio::pin!(send);
+    let mut renewal = tokio::time::interval((lease_duration / 3).max(MIN_LEASE_RENEWAL_INTERVAL));
+    // The first tick is immediate, and the lease was only just taken.
+    renewal.tick().await;
+    loop {
+        tokio::select! {
+            outcome = &mut send => return outcome,
+            _ = renewal.tick() => {
+                if let Err(e) = renew_leases(pool, worker_id, lease_duration).await {
+                    tracing::warn!(
+                        error.cause_chain = ?e,
+                        error.message = %e,
+                        "Failed to renew the lease on delivery tasks",
+                    );
+                }
+            }
+        }
     }
 }
 
+const MIN_LEASE_RENEWAL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exponential backoff with up to 50% random jitter, so that a burst of
+/// failures does not come back as a burst of retries.
+pub(crate) fn retry_delay(base_delay: Duration, n_retries: i16) -> Duration {
+    let backoff = base_delay.saturating_mul(1 << n_retries.clamp(0, 16));
+    let max_jitter = backoff.as_millis() as u64 / 2;
+    let jitter = rand::thread_rng().gen_range(0..=max_jitter);
+    backoff + Duration::from_millis(jitter)
+}
+
+/// How long to defer the `nth` throttled task of a domain, counting from zero.
+/// Each task waits one more `wait` than the previous one, plus up to half a
+/// `wait` of jitter, so they come back as the budget refills instead of all at once.
+pub(crate) fn deferral_delay(wait: Duration, nth: u32) -> Duration {
+    let staggered = wait.saturating_mul(nth.saturating_add(1));
+    let max_jitter = wait.as_millis() as u64 / 2;
+    let jitter = rand::thread_rng().gen_range(0..=max_jitter);
+    staggered + Duration::from_millis(jitter)
+}
+
 #[tracing::instrument(skip_all)]
-async fn delete_task(
-    mut transaction: PgTransaction,
-    issue_id: Uuid,
-    email: &str,
+async fn schedule_retry(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    worker_id: &str,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    let execute_after = Utc::now() + chrono::Duration::from_std(delay)?;
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            n_retries = n_retries + 1,
+            execute_after = $4,
+            leased_until = NULL,
+            leased_by = NULL
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2 AND
+            leased_by = $3
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        worker_id,
+        execute_after
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Puts the task back in the queue until `execute_after` without counting an attempt,
+/// so workers move on to recipients whose domain still has budget.
+#[tracing::instrument(skip_all)]
+async fn defer_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    worker_id: &str,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    let execute_after = Utc::now() + chrono::Duration::from_std(delay)?;
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET execute_after = $4, leased_until = NULL, leased_by = NULL
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2 AND
+            leased_by = $3
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        worker_id,
+        execute_after
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Gives the task back to the queue without counting an attempt.
+#[tracing::instrument(skip_all)]
+async fn release_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    worker_id: &str,
 ) -> Result<(), anyhow::Error> {
     sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET leased_until = NULL, leased_by = NULL
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2 AND
+            leased_by = $3
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        worker_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    worker_id: &str,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
         r#"
         DELETE FROM issue_delivery_queue
         WHERE
             newsletter_issue_id = $1 AND
-            subscriber_email = $2
+            subscriber_email = $2 AND
+            leased_by = $3
         "#,
-        issue_id,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        worker_id
+    )
+    .execute(transaction)
+    .await?;
+    // Another worker reclaimed the task after our lease ran out; it owns the outcome now.
+    if result.rows_affected() == 0 {
+        tracing::warn!(
+            subscriber_email = %task.subscriber_email,
+            "Lost the lease on a delivery task before recording its outcome",
+        );
+    }
+    Ok(result.rows_affected() > 0)
+}
+
+/// Moves a task that will not be retried automatically to the dead-letter table,
+/// where an admin can inspect it and put it back in the queue.
+#[tracing::instrument(skip_all)]
+async fn dead_letter_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    worker_id: &str,
+    n_attempts: i16,
+    last_error: &str,
+    provider_response: Option<String>,
+) -> Result<(), anyhow::Error> {
+    if !delete_task(transaction, task, worker_id).await? {
+        return Ok(());
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_failures (
+            failure_id,
+            newsletter_issue_id,
+            subscriber_email,
+            n_attempts,
+            last_error,
+            provider_response,
+            failed_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, now())
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET
+            n_attempts = issue_delivery_failures.n_attempts + EXCLUDED.n_attempts,
+            last_error = EXCLUDED.last_error,
+            provider_response = EXCLUDED.provider_response,
+            failed_at = EXCLUDED.failed_at
+        "#,
+        Uuid::new_v4(),
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        n_attempts,
+        last_error,
+        provider_response
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Stops every future email to an address the provider refuses to deliver to.
+#[tracing::instrument(skip(transaction))]
+pub(crate) async fn suppress_subscriber(
+    transaction: &mut PgTransaction,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'suppressed' WHERE email = $1"#,
         email
     )
-    .execute(&mut transaction)
+    .execute(&mut *transaction)
+    .await?;
+    suppressions::suppress(
+        transaction,
+        email,
+        "inactive_recipient",
+        suppressions::SOURCE_PROVIDER,
+    )
     .await?;
-    transaction.commit().await?;
     Ok(())
 }
 
+/// Whether a failed task means no email can go out until someone fixes the provider setup.
+pub(crate) fn is_configuration_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<SendEmailError>()
+        .is_some_and(|e| e.kind() == SendEmailErrorKind::Configuration)
+}
+
 #[tracing::instrument(skip_all)]
 async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsLetterIssue, anyhow::Error> {
     let issue = sqlx::query_as!(NewsLetterIssue,
-      r#"SELECT title, text_content, html_content FROM newsletter_issues where newsletter_issue_id = $1"#, 
+      r#"SELECT title, text_content, html_content, track_opens, track_clicks FROM newsletter_issues where newsletter_issue_id = $1"#, 
       issue_id)
     .fetch_one(pool)
     .await?;
@@ -96,74 +391,843 @@ async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsLetterIssue, any
 }
 
 #[tracing::instrument(skip_all)]
+async fn get_subscription_ids(
+    pool: &PgPool,
+    emails: &[String],
+) -> Result<HashMap<String, Uuid>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT id, email FROM subscriptions WHERE email = ANY($1)"#,
+        emails
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(|r| (r.email, r.id)).collect())
+}
+
+struct DeliveryGuard {
+    idempotency_key: String,
+    sent: bool,
+}
+
+/// Makes sure every recipient has a guard before anything is sent and returns them by email.
+/// A guard is created once per issue and subscriber, so retries reuse its idempotency key.
+#[tracing::instrument(skip_all)]
+async fn guard_deliveries(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    emails: &[String],
+) -> Result<HashMap<String, DeliveryGuard>, sqlx::Error> {
+    let keys: Vec<Uuid> = emails.iter().map(|_| Uuid::new_v4()).collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_guards (
+            newsletter_issue_id,
+            subscriber_email,
+            idempotency_key,
+            created_at
+        )
+        SELECT $1, subscriber_email, idempotency_key, now()
+        FROM UNNEST($2::text[], $3::uuid[]) AS t(subscriber_email, idempotency_key)
+        ON CONFLICT DO NOTHING
+        "#,
+        newsletter_issue_id,
+        emails,
+        &keys,
+    )
+    .execute(pool)
+    .await?;
+    let rows = sqlx::query!(
+        r#"
+        SELECT subscriber_email, idempotency_key, sent_at
+        FROM issue_delivery_guards
+        WHERE newsletter_issue_id = $1 AND subscriber_email = ANY($2)
+        "#,
+        newsletter_issue_id,
+        emails,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            let guard = DeliveryGuard {
+                idempotency_key: r.idempotency_key.to_string(),
+                sent: r.sent_at.is_some(),
+            };
+            (r.subscriber_email, guard)
+        })
+        .collect())
+}
+
+/// Marks the guards as sent as soon as the provider accepted the emails. This commits on its own,
+/// ahead of the transaction recording the outcome, so that it survives a crash in between.
+#[tracing::instrument(skip_all)]
+async fn mark_sent(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    emails: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_guards
+        SET sent_at = now()
+        WHERE newsletter_issue_id = $1 AND subscriber_email = ANY($2)
+        "#,
+        newsletter_issue_id,
+        emails,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// RFC 8058: mailbox providers show an unsubscribe button and POST to the link directly.
+fn unsubscribe_headers(link: &str) -> Vec<EmailHeader> {
+    vec![
+        EmailHeader::new("List-Unsubscribe", format!("<{link}>")),
+        EmailHeader::new("List-Unsubscribe-Post", "List-Unsubscribe=One-Click"),
+    ]
+}
+
+/// Appends the attempt to `email_deliveries`, which outlives the task itself.
+#[tracing::instrument(skip(transaction, task, outcome), fields(subscriber_email = %task.subscriber_email))]
+async fn log_delivery_attempt(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    outcome: Result<&SentEmail, &SendEmailError>,
+) -> Result<(), sqlx::Error> {
+    let (outcome, error, provider_message_id, provider) = match outcome {
+        Ok(sent) => (
+            "sent",
+            None,
+            sent.message_id.as_deref(),
+            sent.provider.as_deref(),
+        ),
+        Err(e) => {
+            let outcome = match e.kind() {
+                SendEmailErrorKind::Transient => "transient_failure",
+                SendEmailErrorKind::PermanentRecipient => "permanent_failure",
+                SendEmailErrorKind::PermanentMessage => "rejected",
+                SendEmailErrorKind::Configuration => "configuration_failure",
+            };
+            (outcome, Some(e.to_string()), None, None)
+        }
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO email_deliveries (
+            delivery_id,
+            newsletter_issue_id,
+            subscriber_email,
+            attempt,
+            attempted_at,
+            outcome,
+            error,
+            provider_message_id,
+            provider
+        )
+        VALUES ($1, $2, $3, $4, now(), $5, $6, $7, $8)
+        "#,
+        Uuid::new_v4(),
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        task.n_retries + 1,
+        outcome,
+        error,
+        provider_message_id,
+        provider,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Records the result of one send attempt in the queue and returns the kind of failure, if any.
+/// Configuration errors only release the lease, since they say nothing about the recipient.
+async fn record_outcome(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    worker_id: &str,
+    outcome: Result<&SentEmail, &SendEmailError>,
+    delivery: &DeliverySettings,
+) -> Result<Option<SendEmailErrorKind>, anyhow::Error> {
+    log_delivery_attempt(transaction, task, outcome).await?;
+    let e = match outcome {
+        Ok(_) => {
+            delete_task(transaction, task, worker_id).await?;
+            return Ok(None);
+        }
+        Err(e) => e,
+    };
+    match e.kind() {
+        SendEmailErrorKind::Configuration => release_task(transaction, task, worker_id).await?,
+        SendEmailErrorKind::PermanentRecipient => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                subscriber_email = %task.subscriber_email,
+                "The email provider will not deliver to this subscriber. \
+                 Suppressing the address.",
+            );
+            suppress_subscriber(transaction, &task.subscriber_email).await?;
+            dead_letter_task(
+                transaction,
+                task,
+                worker_id,
+                task.n_retries + 1,
+                &e.to_string(),
+                e.provider_response(),
+            )
+            .await?;
+        }
+        SendEmailErrorKind::PermanentMessage => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                subscriber_email = %task.subscriber_email,
+                "The email provider refused the email. Giving up on it.",
+            );
+            dead_letter_task(
+                transaction,
+                task,
+                worker_id,
+                task.n_retries + 1,
+                &e.to_string(),
+                e.provider_response(),
+            )
+            .await?;
+        }
+        SendEmailErrorKind::Transient if task.n_retries + 1 < delivery.max_attempts => {
+            let delay = retry_delay(delivery.retry_base_delay(), task.n_retries);
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                subscriber_email = %task.subscriber_email,
+                n_retries = task.n_retries,
+                retry_in_ms = delay.as_millis() as u64,
+                "Failed to deliver issue to a confirmed subscriber. \
+                 Scheduling a retry.",
+            );
+            schedule_retry(transaction, task, worker_id, delay).await?;
+        }
+        SendEmailErrorKind::Transient => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                subscriber_email = %task.subscriber_email,
+                n_retries = task.n_retries,
+                "Failed to deliver issue to a confirmed subscriber. \
+                 Giving up after {} attempts.",
+                delivery.max_attempts,
+            );
+            dead_letter_task(
+                transaction,
+                task,
+                worker_id,
+                task.n_retries + 1,
+                &e.to_string(),
+                e.provider_response(),
+            )
+            .await?;
+        }
+    }
+    Ok(Some(e.kind()))
+}
+
+fn configuration_error(e: SendEmailError) -> anyhow::Error {
+    anyhow::Error::new(e).context("The email provider rejected our configuration")
+}
+
+#[tracing::instrument(skip(pool, email_client, base_url, hmac_secret, delivery))]
 pub async fn try_execute_task(
     pool: &PgPool,
-    email_client: &EmailClient,
+    email_client: &dyn EmailSender,
+    base_url: &str,
+    hmac_secret: &Secret<String>,
+    delivery: &DeliverySettings,
+    worker_id: &str,
 ) -> Result<ExecutionOutcome, anyhow::Error> {
-    let task = dequeue_task(pool).await?;
-    if task.is_none() {
+    execute_tasks(
+        pool,
+        email_client,
+        base_url,
+        hmac_secret,
+        delivery,
+        worker_id,
+        1,
+    )
+    .await
+}
+
+/// Delivers up to `delivery.batch_size` tasks of one issue with a single call to the provider,
+/// or fewer when the backend cannot send that many at once.
+/// Each message is retried, dead-lettered or suppressed on its own.
+#[tracing::instrument(skip(pool, email_client, base_url, hmac_secret, delivery))]
+pub async fn try_execute_batch(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    base_url: &str,
+    hmac_secret: &Secret<String>,
+    delivery: &DeliverySettings,
+    worker_id: &str,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    execute_tasks(
+        pool,
+        email_client,
+        base_url,
+        hmac_secret,
+        delivery,
+        worker_id,
+        delivery.batch_size().min(email_client.max_batch_size()),
+    )
+    .await
+}
+
+#[tracing::instrument(
+    skip(pool, email_client, base_url, hmac_secret, delivery),
+    fields(news_letter_issue_id=tracing::field::Empty, n_tasks=tracing::field::Empty)
+)]
+async fn execute_tasks(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    base_url: &str,
+    hmac_secret: &Secret<String>,
+    delivery: &DeliverySettings,
+    worker_id: &str,
+    batch_size: usize,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let tasks = claim_tasks(
+        pool,
+        worker_id,
+        delivery.lease_duration(),
+        batch_size as i64,
+    )
+    .await?;
+    if tasks.is_empty() {
         return Ok(ExecutionOutcome::EmptyQueue);
     }
-    let (transaction, issue_id, email) = task.unwrap();
+    let newsletter_issue_id = tasks[0].newsletter_issue_id;
     Span::current()
-        .record("news_letter_issue_id", &display(issue_id))
-        .record("subscriber_email", &display(&email));
-    match SubscriberEmail::parse(email.clone()) {
-        Ok(email) => {
-            let issue = get_issue(pool, issue_id).await?;
-            if let Err(e) = email_client
-                .send_email(
-                    &email,
-                    &issue.title,
-                    &issue.html_content,
-                    &issue.text_content,
-                )
+        .record("news_letter_issue_id", display(newsletter_issue_id))
+        .record("n_tasks", tasks.len());
+
+    let mut recipients = Vec::with_capacity(tasks.len());
+    let mut invalid = Vec::new();
+    for task in &tasks {
+        match SubscriberEmail::parse(task.subscriber_email.clone()) {
+            Ok(email) => recipients.push((task, email)),
+            Err(e) => invalid.push((task, e)),
+        }
+    }
+    let emails: Vec<_> = recipients
+        .iter()
+        .map(|(task, _)| task.subscriber_email.clone())
+        .collect();
+    let suppressed = suppressions::suppressed_among(pool, &emails).await?;
+    let (suppressed, recipients): (Vec<_>, Vec<_>) = recipients
+        .into_iter()
+        .partition(|(task, _)| suppressed.contains(&task.subscriber_email));
+    let emails: Vec<_> = recipients
+        .iter()
+        .map(|(task, _)| task.subscriber_email.clone())
+        .collect();
+    let guards = guard_deliveries(pool, newsletter_issue_id, &emails).await?;
+    // The provider accepted these before, but we went down before recording it.
+    let (already_sent, recipients): (Vec<_>, Vec<_>) = recipients
+        .into_iter()
+        .partition(|(task, _)| guards.get(&task.subscriber_email).is_some_and(|g| g.sent));
+    // Only emails that actually go out draw from the budget of their domain.
+    let (mut within_budget, mut throttled) = (Vec::with_capacity(recipients.len()), Vec::new());
+    for (task, email) in recipients {
+        match email_client.try_reserve_for(email.as_ref()) {
+            Ok(()) => within_budget.push((task, email)),
+            Err(wait) => throttled.push((task, wait)),
+        }
+    }
+    let recipients = within_budget;
+    let issue = get_issue(pool, newsletter_issue_id).await?;
+    let subscription_ids = get_subscription_ids(pool, &emails).await?;
+    let headers: Vec<_> = recipients
+        .iter()
+        .map(|(_, email)| match subscription_ids.get(email.as_ref()) {
+            Some(subscription_id) => {
+                unsubscribe_headers(&unsubscribe_link(base_url, hmac_secret, subscription_id))
+            }
+            None => vec![],
+        })
+        .collect();
+    let track_opens = delivery.track_opens && issue.track_opens;
+    let track_clicks = delivery.track_clicks && issue.track_clicks;
+    let html_contents: Vec<_> = recipients
+        .iter()
+        .map(|(_, email)| match subscription_ids.get(email.as_ref()) {
+            Some(subscription_id) if track_opens || track_clicks => {
+                let mut html_content = issue.html_content.clone();
+                if track_clicks {
+                    html_content = rewrite_links(&html_content, |url| {
+                        click_url(
+                            base_url,
+                            hmac_secret,
+                            &newsletter_issue_id,
+                            subscription_id,
+                            url,
+                        )
+                    });
+                }
+                if track_opens {
+                    let pixel_url = open_pixel_url(
+                        base_url,
+                        hmac_secret,
+                        &newsletter_issue_id,
+                        subscription_id,
+                    );
+                    html_content = add_open_pixel(&html_content, &pixel_url);
+                }
+                Cow::Owned(html_content)
+            }
+            _ => Cow::Borrowed(issue.html_content.as_str()),
+        })
+        .collect();
+    let messages: Vec<_> = recipients
+        .iter()
+        .zip(&headers)
+        .zip(&html_contents)
+        .map(|(((_, email), headers), html_content)| OutgoingEmail {
+            recipient: email,
+            subject: &issue.title,
+            html_content,
+            text_content: &issue.text_content,
+            headers,
+            idempotency_key: guards
+                .get(email.as_ref())
+                .map(|g| g.idempotency_key.as_str()),
+        })
+        .collect();
+
+    // A single task goes through the plain endpoint, which reports errors with a proper status.
+    let send = async {
+        match messages.as_slice() {
+            [] => Ok(vec![]),
+            [message] => Ok(vec![email_client.send(message).await]),
+            messages => email_client
+                .send_batch(messages)
                 .await
-            {
-                tracing::error!(
-                error.cause_chain = ?e,
-                error.message = %e,
-                "Failed to deliver issue to a confirmed subscriber. \
-                                         Skipping.",
-                                    );
+                .map(|outcomes| email_client::one_outcome_per_email(outcomes, messages.len())),
+        }
+    };
+    let outcome = renewing_leases(pool, worker_id, delivery.lease_duration(), send).await;
+    if let Ok(outcomes) = &outcome {
+        let sent: Vec<_> = recipients
+            .iter()
+            .zip(outcomes)
+            .filter(|(_, outcome)| outcome.is_ok())
+            .map(|((task, _), _)| task.subscriber_email.clone())
+            .collect();
+        mark_sent(pool, newsletter_issue_id, &sent).await?;
+    }
+
+    // Only now, with the provider's answer in hand, do we need a transaction.
+    let mut transaction = pool.begin().await?;
+    if !throttled.is_empty() {
+        tracing::info!(
+            n_throttled = throttled.len(),
+            "Deferring tasks whose recipient domain is over its rate limit",
+        );
+    }
+    let mut n_deferred_per_domain: HashMap<String, u32> = HashMap::new();
+    for (task, wait) in throttled {
+        let domain = task.subscriber_email.rsplit('@').next().unwrap_or_default();
+        let nth = n_deferred_per_domain
+            .entry(domain.to_lowercase())
+            .or_default();
+        defer_task(
+            &mut transaction,
+            task,
+            worker_id,
+            deferral_delay(wait, *nth),
+        )
+        .await?;
+        *nth += 1;
+    }
+    for (task, _) in suppressed {
+        tracing::info!(
+            subscriber_email = %task.subscriber_email,
+            "The address is on the suppression list. Dropping the task",
+        );
+        delete_task(&mut transaction, task, worker_id).await?;
+    }
+    for (task, _) in already_sent {
+        tracing::warn!(
+            subscriber_email = %task.subscriber_email,
+            "The issue was already sent to this subscriber. Dropping the task",
+        );
+        delete_task(&mut transaction, task, worker_id).await?;
+    }
+    for (task, e) in invalid {
+        tracing::error!(
+            error.cause_chain = ?e,
+            error.message = %e,
+            subscriber_email = %task.subscriber_email,
+            "Skipping a confirmed subscriber. \
+             Their stored contact details are invalid",
+        );
+        dead_letter_task(&mut transaction, task, worker_id, task.n_retries, &e, None).await?;
+    }
+    let mut configuration_failure = None;
+    match outcome {
+        Ok(outcomes) => {
+            for ((task, _), outcome) in recipients.iter().zip(outcomes) {
+                let kind = record_outcome(
+                    &mut transaction,
+                    task,
+                    worker_id,
+                    outcome.as_ref(),
+                    delivery,
+                )
+                .await?;
+                if kind == Some(SendEmailErrorKind::Configuration) {
+                    configuration_failure = configuration_failure.or(outcome.err());
+                }
             }
         }
+        // The whole call failed, so every message in it failed for the same reason.
         Err(e) => {
-            tracing::error!(
-                error.cause_chain = ?e,
-                error.message = %e,
-                "Skipping a confirmed subscriber. \
-                 Their stored contact details are invalid",
-            );
+            for (task, _) in &recipients {
+                record_outcome(&mut transaction, task, worker_id, Err(&e), delivery).await?;
+            }
+            if e.kind() == SendEmailErrorKind::Configuration {
+                configuration_failure = Some(e);
+            }
         }
     }
-    delete_task(transaction, issue_id, &email).await?;
-    Ok(ExecutionOutcome::TaskCompleted)
+    transaction.commit().await?;
+    match configuration_failure {
+        Some(e) => Err(configuration_error(e)),
+        None => Ok(ExecutionOutcome::TaskCompleted),
+    }
 }
 
-async fn worker_loop(pool: PgPool, email_client: EmailClient) -> Result<(), anyhow::Error> {
+#[allow(clippy::too_many_arguments)]
+async fn worker_loop(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    base_url: String,
+    hmac_secret: Secret<String>,
+    delivery: DeliverySettings,
+    worker: WorkerSettings,
+    worker_id: String,
+    mut wake: watch::Receiver<()>,
+    mut shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
     loop {
-        match try_execute_task(&pool, &email_client).await {
+        // Only checked between batches: a batch that was claimed is always sent and recorded.
+        if shutdown.is_requested() {
+            return Ok(());
+        }
+        // Anything notified from here on may not be visible to the claim below, so it must wake us again.
+        wake.borrow_and_update();
+        match try_execute_batch(
+            &pool,
+            email_client.as_ref(),
+            &base_url,
+            &hmac_secret,
+            &delivery,
+            &worker_id,
+        )
+        .await
+        {
+            // New issues wake us up; retries, deferred tasks and expired leases do not,
+            // so we sleep until the next of them is due. Polling at the idle interval is
+            // only a fallback for notifications that got lost, e.g. while the listener
+            // was reconnecting.
             Ok(ExecutionOutcome::EmptyQueue) => {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+                let idle = match time_until_next_task(&pool).await {
+                    Ok(Some(wait)) => wait.clamp(MIN_IDLE_SLEEP, worker.idle_poll_interval()),
+                    Ok(None) => worker.idle_poll_interval(),
+                    Err(e) => {
+                        tracing::warn!(
+                            error.cause_chain = ?e,
+                            error.message = %e,
+                            "Failed to look up when the next delivery task is due",
+                        );
+                        worker.idle_poll_interval()
+                    }
+                };
+                tokio::select! {
+                    _ = wake.changed() => {}
+                    _ = shutdown.sleep(idle) => {}
+                }
+            }
+            Err(e) if is_configuration_error(&e) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Stopping the delivery worker until the email provider setup is fixed",
+                );
+                return Err(e);
             }
-            Err(_) => {
-                tokio::time::sleep(Duration::from_secs(1)).await;
+            Err(e) => {
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    worker_id,
+                    "Failed to execute a delivery task",
+                );
+                shutdown.sleep(worker.error_backoff()).await;
             }
             Ok(ExecutionOutcome::TaskCompleted) => {}
         }
     }
 }
 
-pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+/// A task can be due and still not claimed, e.g. while another worker holds the lock on it:
+/// this keeps an idle loop from spinning in the meantime.
+const MIN_IDLE_SLEEP: Duration = Duration::from_millis(100);
+
+/// How long until some task in the queue can be claimed, or `None` if the queue is empty.
+#[tracing::instrument(skip_all)]
+async fn time_until_next_task(pool: &PgPool) -> Result<Option<Duration>, anyhow::Error> {
+    let next_due = sqlx::query!(
+        r#"
+        SELECT min(GREATEST(execute_after, leased_until)) AS next_due
+        FROM issue_delivery_queue
+        "#
+    )
+    .fetch_one(pool)
+    .await?
+    .next_due;
+    Ok(next_due.map(|next_due| (next_due - Utc::now()).to_std().unwrap_or(Duration::ZERO)))
+}
+
+/// Listens on `channel` through a connection of its own and wakes the idle loops.
+/// It never returns on its own.
+pub(crate) async fn listen_for_new_tasks(
+    pool: PgPool,
+    channel: &'static str,
+    wake: watch::Sender<()>,
+    error_backoff: Duration,
+) -> Result<(), anyhow::Error> {
+    loop {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    channel,
+                    "Failed to connect the queue listener, falling back to polling",
+                );
+                tokio::time::sleep(error_backoff).await;
+                continue;
+            }
+        };
+        if let Err(e) = listener.listen(channel).await {
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                channel,
+                "Failed to listen for new tasks, falling back to polling",
+            );
+            tokio::time::sleep(error_backoff).await;
+            continue;
+        }
+        loop {
+            match listener.try_recv().await {
+                Ok(Some(_)) => {
+                    wake.send_replace(());
+                }
+                // The connection dropped and was re-established: whatever was
+                // notified in between is lost, so have a look at the queue anyway.
+                Ok(None) => {
+                    wake.send_replace(());
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        error.cause_chain = ?e,
+                        error.message = %e,
+                        channel,
+                        "Lost the queue listener",
+                    );
+                    tokio::time::sleep(error_backoff).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Runs the loop `new_loop` makes, and a fresh one whenever it panics: a panic is a bug
+/// in handling one batch and must not quietly take a worker out for good.
+async fn restart_on_panic<F, Fut>(
+    mut new_loop: F,
+    mut shutdown: Shutdown,
+    error_backoff: Duration,
+) -> Result<(), anyhow::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), anyhow::Error>> + Send + 'static,
+{
+    loop {
+        match tokio::spawn(new_loop()).await {
+            Ok(outcome) => return outcome,
+            Err(e) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "A delivery worker panicked, restarting it",
+                );
+                shutdown.sleep(error_backoff).await;
+                if shutdown.is_requested() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Runs `worker.concurrency` delivery loops side by side. Since tasks are leased,
+/// the loops never pick the same task and a slow or failing loop does not hold up the rest.
+/// Idle loops are woken by `listen_for_new_tasks` as soon as tasks are enqueued.
+/// Once `shutdown` is requested, or a loop stops on a configuration error, the loops
+/// finish the batch they are sending and return.
+/// `email_client` is shared with the outbox worker, so that both draw from the same rate limits.
+pub async fn run_worker_until_stopped(
+    configuration: Settings,
+    email_client: Arc<dyn EmailSender>,
+    mut shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
     let connection_pool = get_connection_pool(&configuration);
+    let process_id = Uuid::new_v4();
+    let (wake, wake_receiver) = watch::channel(());
+    let (stop_workers, workers_shutdown) = shutdown_channel();
+
+    let mut workers = JoinSet::new();
+    let listener = listen_for_new_tasks(
+        connection_pool.clone(),
+        DELIVERY_QUEUE_CHANNEL,
+        wake,
+        configuration.worker.error_backoff(),
+    );
+    let mut listener_shutdown = workers_shutdown.clone();
+    workers.spawn(async move {
+        tokio::select! {
+            outcome = listener => outcome,
+            _ = listener_shutdown.requested() => Ok(()),
+        }
+    });
+    for n in 0..configuration.worker.concurrency.max(1) {
+        let pool = connection_pool.clone();
+        let email_client = email_client.clone();
+        let base_url = configuration.application.base_url.clone();
+        let hmac_secret = configuration.application.hmac_secret.clone();
+        let delivery = configuration.delivery.clone();
+        let worker = configuration.worker.clone();
+        let worker_id = format!("delivery-worker-{process_id}-{n}");
+        let wake = wake_receiver.clone();
+        let shutdown = workers_shutdown.clone();
+        workers.spawn(restart_on_panic(
+            move || {
+                worker_loop(
+                    pool.clone(),
+                    email_client.clone(),
+                    base_url.clone(),
+                    hmac_secret.clone(),
+                    delivery.clone(),
+                    worker.clone(),
+                    worker_id.clone(),
+                    wake.clone(),
+                    shutdown.clone(),
+                )
+            },
+            workers_shutdown.clone(),
+            configuration.worker.error_backoff(),
+        ));
+    }
+    let mut failure = None;
+    loop {
+        let outcome = tokio::select! {
+            outcome = workers.join_next() => match outcome {
+                Some(outcome) => outcome,
+                None => break,
+            },
+            _ = shutdown.requested(), if !workers_shutdown.is_requested() => {
+                stop_workers.trigger();
+                continue;
+            }
+        };
+        match outcome {
+            Ok(Ok(())) => {}
+            // Only a configuration error ends a loop, and it would end every other loop too.
+            // They are stopped like on shutdown, so that no batch is left sent but unrecorded.
+            Ok(Err(e)) => {
+                stop_workers.trigger();
+                failure = failure.or(Some(e));
+            }
+            Err(e) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "The delivery queue listener panicked",
+                );
+            }
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deferral_delay, restart_on_panic, retry_delay};
+    use crate::shutdown::Shutdown;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
-    let sender_email = configuration
-        .email_client
-        .sender()
-        .expect("Invalid sender email address");
+    #[test]
+    fn retry_delay_doubles_with_each_attempt_and_adds_bounded_jitter() {
+        let base = Duration::from_secs(30);
+        for n_retries in 0..5 {
+            let backoff = base * 2u32.pow(n_retries as u32);
+            let delay = retry_delay(base, n_retries);
+            assert!(delay >= backoff);
+            assert!(delay <= backoff + backoff / 2);
+        }
+    }
+
+    #[test]
+    fn deferred_tasks_of_a_domain_are_spread_one_wait_apart() {
+        let wait = Duration::from_secs(2);
+        for nth in 0..5 {
+            let staggered = wait * (nth + 1);
+            let delay = deferral_delay(wait, nth);
+            assert!(delay >= staggered);
+            assert!(delay <= staggered + wait / 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_worker_loop_that_panics_is_started_again() {
+        let n_runs = Arc::new(AtomicUsize::new(0));
 
-    let timeout = configuration.email_client.timeout();
-    let email_client = configuration.email_client.client();
-    worker_loop(connection_pool, email_client).await
+        let outcome = restart_on_panic(
+            || {
+                let n_runs = n_runs.clone();
+                async move {
+                    if n_runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("Failed to handle a batch");
+                    }
+                    Ok(())
+                }
+            },
+            Shutdown::never(),
+            Duration::from_millis(10),
+        )
+        .await;
+
+        assert!(outcome.is_ok());
+        assert_eq!(n_runs.load(Ordering::SeqCst), 2);
+    }
 }
@@ -1,11 +1,18 @@
 pub mod authentication;
+pub mod circuit_breaker;
 pub mod configuration;
 pub mod domains;
 pub mod email_client;
+pub mod email_outbox_worker;
 pub mod idempotency;
 pub mod issue_delivery_worker;
+pub mod rate_limiter;
 pub mod routes;
+pub mod shutdown;
 pub mod startup;
 pub mod state_session;
+pub mod subscription_cleanup_worker;
+pub mod suppressions;
 pub mod telemetry;
+pub mod tracking;
 pub mod utils;
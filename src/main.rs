@@ -1,48 +1,155 @@
-use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
 
+use anyhow::Context;
 use news_letter::{
     configuration::get_configuration,
+    email_client::EmailSender,
+    email_outbox_worker::run_outbox_worker_until_stopped,
     issue_delivery_worker::run_worker_until_stopped,
+    shutdown::{shutdown_channel, shutdown_signal},
     startup::Application,
+    subscription_cleanup_worker::run_cleanup_worker_until_stopped,
     telemetry::{get_subscriber, init_subscriber},
 };
-use tokio::task::JoinError;
+use tokio::task::{JoinError, JoinSet};
+
+/// The name of the task, whether the process stops when it exits, and how it exited.
+type TaskOutcome = (&'static str, bool, Result<(), anyhow::Error>);
+
+const USAGE: &str = "Usage: news_letter [serve|worker|all]";
+
+/// What the process runs, so the API and the workers can be scaled separately.
+#[derive(PartialEq)]
+enum Command {
+    /// Only the HTTP API.
+    Serve,
+    /// Only the background workers; needs neither Redis nor a port.
+    Worker,
+    /// Both, in one process.
+    All,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "serve" => Ok(Command::Serve),
+            "worker" => Ok(Command::Worker),
+            "all" => Ok(Command::All),
+            other => Err(anyhow::anyhow!("Unknown command `{other}`. {USAGE}")),
+        }
+    }
+}
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
+    let command = match std::env::args().nth(1) {
+        Some(arg) => arg.parse()?,
+        None => Command::All,
+    };
     let settings = get_configuration().expect("Unable to read configuration files");
     let subscriber = get_subscriber("news_letter".into(), "info".into(), std::io::stdout);
     init_subscriber(subscriber);
-    let app = Application::build(&settings)
-        .await
-        .expect("Unable to build application");
-    let worker = run_worker_until_stopped(settings);
-
-    let application_task = tokio::spawn(app.run_until_stopped());
-    let worker_task = tokio::spawn(worker);
-    tokio::select! {
-        o = application_task => report_exit("API", o),
-        o = worker_task => report_exit("BACKGROUN_WORKER", o),
-    };
+    let shutdown_timeout = settings.application.shutdown_timeout();
+    let (shutdown_trigger, shutdown) = shutdown_channel();
+
+    let mut tasks = JoinSet::new();
+    if command != Command::Worker {
+        let app = Application::build(&settings)
+            .await
+            .expect("Unable to build application");
+        let shutdown = shutdown.clone();
+        spawn_task(&mut tasks, "API", true, async move {
+            app.run_until_stopped(shutdown).await.map_err(Into::into)
+        });
+    }
+    if command != Command::Serve {
+        // Next to the API, a worker stopping on a bad provider setup must not take the API down.
+        let critical = command == Command::Worker;
+        // One client for every worker: they share its rate limits and circuit breakers.
+        let email_client: Arc<dyn EmailSender> = Arc::new(settings.email_client.client());
+        let cleanup_worker = run_cleanup_worker_until_stopped(
+            get_configuration().expect("Unable to read configuration files"),
+            shutdown.clone(),
+        );
+        let outbox_worker = run_outbox_worker_until_stopped(
+            get_configuration().expect("Unable to read configuration files"),
+            email_client.clone(),
+            shutdown.clone(),
+        );
+        let worker = run_worker_until_stopped(settings, email_client, shutdown);
+        spawn_task(&mut tasks, "BACKGROUN_WORKER", critical, worker);
+        spawn_task(&mut tasks, "CLEANUP_WORKER", critical, cleanup_worker);
+        spawn_task(&mut tasks, "OUTBOX_WORKER", critical, outbox_worker);
+    }
+
+    // Whether a signal arrives or a critical task exits on its own, the others are stopped gracefully.
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    loop {
+        tokio::select! {
+            Some(outcome) = tasks.join_next() => {
+                if report_exit(outcome) {
+                    break;
+                }
+            }
+            signal = &mut signal => {
+                signal.context("Failed to listen for shutdown signals")?;
+                tracing::info!("Received a shutdown signal");
+                break;
+            }
+        }
+    }
+    shutdown_trigger.trigger();
+
+    let drained = tokio::time::timeout(shutdown_timeout, async {
+        while let Some(outcome) = tasks.join_next().await {
+            report_exit(outcome);
+        }
+    })
+    .await;
+    if drained.is_err() {
+        // Deliveries that were still being sent keep their lease and are retried once it expires.
+        tracing::error!(
+            "Tasks did not stop within {:?}, exiting anyway",
+            shutdown_timeout
+        );
+    }
     Ok(())
 }
 
-fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>, JoinError>) {
+fn spawn_task(
+    tasks: &mut JoinSet<TaskOutcome>,
+    task_name: &'static str,
+    critical: bool,
+    task: impl Future<Output = Result<(), anyhow::Error>> + Send + 'static,
+) {
+    tasks.spawn(async move { (task_name, critical, task.await) });
+}
+
+/// Logs how a task exited and returns whether the process should stop.
+/// A panic does not say which task it was, so it always stops the process.
+fn report_exit(outcome: Result<TaskOutcome, JoinError>) -> bool {
     match outcome {
-        Ok(Ok(())) => {
-            tracing::info!("{} has exited", task_name)
+        Ok((task_name, critical, Ok(()))) => {
+            tracing::info!("{} has exited", task_name);
+            critical
         }
-        Ok(Err(e)) => {
+        Ok((task_name, critical, Err(e))) => {
             tracing::error!(      error.cause_chain = ?e,
                 error.message = %e,
                 "{} failed",
-                task_name)
+                task_name);
+            critical
         }
         Err(e) => {
             tracing::error!(      error.cause_chain = ?e,
                 error.message = %e,
-                "{} failed",
-                task_name)
+                "A task panicked");
+            true
         }
     }
 }
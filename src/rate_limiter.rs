@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A token bucket: up to `burst` messages can go out at once, after which
+/// sending is smoothed to `messages_per_second`.
+/// Every clone of the `Arc` holding it draws from the same bucket.
+pub struct RateLimiter {
+    messages_per_second: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Panics unless `messages_per_second` is a positive number.
+    pub fn new(messages_per_second: f64, burst: u32) -> Self {
+        assert!(
+            messages_per_second.is_finite() && messages_per_second > 0.0,
+            "messages_per_second must be a positive number, got {messages_per_second}"
+        );
+        let burst = f64::from(burst.max(1));
+        Self {
+            messages_per_second,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Waits until `n` messages may be sent. Requests larger than the burst size
+    /// are served in burst-sized slices.
+    pub async fn acquire(&self, n: usize) {
+        let mut remaining = n as f64;
+        while remaining > 0.0 {
+            let wanted = remaining.min(self.burst);
+            match self.try_acquire(wanted) {
+                Ok(()) => remaining -= wanted,
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Stops handing out tokens for `duration`, e.g. when the provider answers `429 Too Many Requests`.
+    pub fn pause_for(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.paused_until = Some(bucket.paused_until.map_or(until, |p| p.max(until)));
+        // Whatever accumulated before the pause would otherwise go out in one burst right after it.
+        bucket.tokens = 0.0;
+    }
+
+    /// Takes `n` tokens without waiting, or returns how long until they are available.
+    pub fn try_acquire(&self, n: f64) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock().unwrap();
+        if let Some(paused_until) = bucket.paused_until {
+            if paused_until > now {
+                return Err(paused_until - now);
+            }
+            bucket.paused_until = None;
+            bucket.refilled_at = now;
+        }
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.messages_per_second).min(self.burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= n {
+            bucket.tokens -= n;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (n - bucket.tokens) / self.messages_per_second,
+            ))
+        }
+    }
+}
+
+/// Separate budgets for the mailbox providers that defer mail when it arrives too fast.
+/// Domains without a limit of their own are never held back.
+#[derive(Default)]
+pub struct DomainRateLimiter {
+    limiters: HashMap<String, RateLimiter>,
+}
+
+impl DomainRateLimiter {
+    pub fn new(limits: impl IntoIterator<Item = (String, RateLimiter)>) -> Self {
+        Self {
+            limiters: limits
+                .into_iter()
+                .map(|(domain, limiter)| (domain.to_lowercase(), limiter))
+                .collect(),
+        }
+    }
+
+    /// Takes one message from the budget of the recipient's domain without waiting,
+    /// or returns how long until that domain accepts more.
+    pub fn try_acquire(&self, recipient: &str) -> Result<(), Duration> {
+        let domain = recipient.rsplit('@').next().unwrap_or_default();
+        match self.limiters.get(&domain.to_lowercase()) {
+            Some(limiter) => limiter.try_acquire(1.0),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DomainRateLimiter, RateLimiter};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn a_full_burst_goes_out_immediately() {
+        let limiter = RateLimiter::new(10.0, 5);
+        let start = Instant::now();
+
+        limiter.acquire(5).await;
+
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn sending_beyond_the_burst_is_smoothed_to_the_rate() {
+        let limiter = RateLimiter::new(20.0, 5);
+        let start = Instant::now();
+
+        limiter.acquire(9).await;
+
+        // 5 tokens are available straight away, the other 4 take 200ms at 20/s.
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn a_pause_holds_back_every_caller() {
+        let limiter = RateLimiter::new(100.0, 100);
+        let start = Instant::now();
+
+        limiter.pause_for(Duration::from_millis(200));
+        limiter.acquire(1).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn domains_are_throttled_independently() {
+        let limiter = DomainRateLimiter::new([("gmail.com".to_string(), RateLimiter::new(1.0, 1))]);
+
+        assert!(limiter.try_acquire("ursula@gmail.com").is_ok());
+        assert!(limiter.try_acquire("le_guin@GMAIL.com").is_err());
+        assert!(limiter.try_acquire("ursula@example.com").is_ok());
+        assert!(limiter.try_acquire("le_guin@example.com").is_ok());
+    }
+}
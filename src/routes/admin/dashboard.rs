@@ -39,6 +39,9 @@ pub async fn admin_dashboard(
     <ol>
         <li><a href="/admin/password">Change password</a></li>
         <li><a href="/admin/newsletters">Send new letter</a></li>
+        <li><a href="/admin/deliveries">Delivery log</a></li>
+        <li><a href="/admin/delivery-failures">Failed deliveries</a></li>
+        <li><a href="/admin/suppressions">Suppressed addresses</a></li>
         <li>
         <form name="logoutForm" action="/admin/logout" method="post">
          <input type="submit" value="Logout">
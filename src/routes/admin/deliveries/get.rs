@@ -0,0 +1,272 @@
+use crate::configuration::DeliverySettings;
+use crate::routes::e500;
+use crate::utils::e400;
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::fmt::Write;
+use uuid::Uuid;
+
+/// The page shows at most this many attempts, newest first.
+const MAX_DELIVERIES: i64 = 500;
+
+// Submitting the filter form sends empty values for the fields left blank.
+#[derive(serde::Deserialize)]
+pub struct DeliveryFilter {
+    newsletter_issue_id: Option<String>,
+    subscriber_email: Option<String>,
+}
+
+struct Delivery {
+    newsletter_issue_id: Uuid,
+    title: String,
+    subscriber_email: String,
+    attempt: i16,
+    attempted_at: DateTime<Utc>,
+    outcome: String,
+    error: Option<String>,
+    provider_message_id: Option<String>,
+    provider: Option<String>,
+}
+
+struct IssueStats {
+    track_opens: bool,
+    track_clicks: bool,
+    n_delivered: i64,
+    n_opened: i64,
+}
+
+struct LinkClicks {
+    url: String,
+    n_clicks: i64,
+    n_clickers: i64,
+}
+
+impl IssueStats {
+    fn open_rate_html(&self) -> String {
+        if !self.track_opens {
+            return "    <p>Opens are not tracked for this issue.</p>\n".into();
+        }
+        // Images blocked by the mail client never reach us: this is a lower bound.
+        let percent = match self.n_delivered {
+            0 => 0.0,
+            n_delivered => 100.0 * self.n_opened as f64 / n_delivered as f64,
+        };
+        format!(
+            "    <p>Opened by {} of {} recipients ({percent:.1}%).</p>\n",
+            self.n_opened, self.n_delivered
+        )
+    }
+
+    fn link_clicks_html(&self, link_clicks: &[LinkClicks]) -> String {
+        if !self.track_clicks {
+            return "    <p>Clicks are not tracked for this issue.</p>\n".into();
+        }
+        if link_clicks.is_empty() {
+            return "    <p>No link has been clicked yet.</p>\n".into();
+        }
+        let mut html = String::from(
+            "    <table>\n        <tr><th>Link</th><th>Clicks</th><th>Subscribers who clicked</th></tr>\n",
+        );
+        for link in link_clicks {
+            writeln!(
+                html,
+                "        <tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                htmlescape::encode_minimal(&link.url),
+                link.n_clicks,
+                link.n_clickers,
+            )
+            .unwrap();
+        }
+        html.push_str("    </table>\n");
+        html
+    }
+}
+
+#[tracing::instrument(name = "Get email deliveries", skip_all)]
+#[get("/deliveries")]
+pub async fn list_deliveries(
+    filter: web::Query<DeliveryFilter>,
+    pool: web::Data<PgPool>,
+    delivery: web::Data<DeliverySettings>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let newsletter_issue_id = filter
+        .newsletter_issue_id
+        .as_deref()
+        .filter(|id| !id.is_empty())
+        .map(Uuid::parse_str)
+        .transpose()
+        .context("Invalid newsletter issue id")
+        .map_err(e400)?;
+    let subscriber_email = filter
+        .subscriber_email
+        .as_deref()
+        .map(str::trim)
+        .filter(|email| !email.is_empty());
+    let deliveries = get_deliveries(&pool, newsletter_issue_id, subscriber_email)
+        .await
+        .map_err(e500)?;
+
+    let mut stats_html = String::new();
+    if let Some(newsletter_issue_id) = newsletter_issue_id {
+        if let Some(mut stats) = get_issue_stats(&pool, newsletter_issue_id)
+            .await
+            .map_err(e500)?
+        {
+            // Turning tracking off globally strips it from every issue, whatever it was published with.
+            stats.track_opens &= delivery.track_opens;
+            stats.track_clicks &= delivery.track_clicks;
+            let link_clicks = get_link_clicks(&pool, newsletter_issue_id)
+                .await
+                .map_err(e500)?;
+            stats_html.push_str(&stats.open_rate_html());
+            stats_html.push_str(&stats.link_clicks_html(&link_clicks));
+        }
+    }
+
+    let mut deliveries_html = String::new();
+    for delivery in &deliveries {
+        writeln!(
+            deliveries_html,
+            r#"        <tr><td><a href="/admin/deliveries?newsletter_issue_id={}">{}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>"#,
+            delivery.newsletter_issue_id,
+            htmlescape::encode_minimal(&delivery.title),
+            htmlescape::encode_minimal(&delivery.subscriber_email),
+            delivery.attempt,
+            delivery.attempted_at.to_rfc3339(),
+            delivery.outcome,
+            htmlescape::encode_minimal(delivery.error.as_deref().unwrap_or("")),
+            htmlescape::encode_minimal(delivery.provider.as_deref().unwrap_or("")),
+            htmlescape::encode_minimal(delivery.provider_message_id.as_deref().unwrap_or("")),
+        )
+        .unwrap();
+    }
+    let deliveries_html = if deliveries.is_empty() {
+        "    <p>No delivery attempts match.</p>".to_string()
+    } else {
+        format!(
+            r#"    <table>
+        <tr><th>Issue</th><th>Subscriber</th><th>Attempt</th><th>Attempted at</th><th>Outcome</th><th>Error</th><th>Provider</th><th>Message ID</th></tr>
+{deliveries_html}    </table>"#
+        )
+    };
+    let issue_value = newsletter_issue_id
+        .map(|id| id.to_string())
+        .unwrap_or_default();
+    let email_value = htmlescape::encode_attribute(subscriber_email.unwrap_or(""));
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Deliveries</title>
+</head>
+<body>
+    <form action="/admin/deliveries" method="get">
+        <label>Issue id
+            <input type="text" name="newsletter_issue_id" value="{issue_value}">
+        </label>
+        <label>Subscriber email
+            <input type="text" name="subscriber_email" value="{email_value}">
+        </label>
+        <button type="submit">Filter</button>
+    </form>
+{stats_html}{deliveries_html}
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_deliveries(
+    pool: &PgPool,
+    newsletter_issue_id: Option<Uuid>,
+    subscriber_email: Option<&str>,
+) -> Result<Vec<Delivery>, sqlx::Error> {
+    sqlx::query_as!(
+        Delivery,
+        r#"
+        SELECT
+            d.newsletter_issue_id,
+            i.title,
+            d.subscriber_email,
+            d.attempt,
+            d.attempted_at,
+            d.outcome,
+            d.error,
+            d.provider_message_id,
+            d.provider
+        FROM email_deliveries d
+        JOIN newsletter_issues i ON i.newsletter_issue_id = d.newsletter_issue_id
+        WHERE
+            ($1::uuid IS NULL OR d.newsletter_issue_id = $1) AND
+            ($2::text IS NULL OR d.subscriber_email = $2)
+        ORDER BY d.attempted_at DESC
+        LIMIT $3
+        "#,
+        newsletter_issue_id,
+        subscriber_email,
+        MAX_DELIVERIES,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_issue_stats(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Option<IssueStats>, sqlx::Error> {
+    sqlx::query_as!(
+        IssueStats,
+        r#"
+        SELECT
+            i.track_opens,
+            i.track_clicks,
+            (
+                SELECT count(DISTINCT subscriber_email)
+                FROM email_deliveries
+                WHERE newsletter_issue_id = $1 AND outcome = 'sent'
+            ) AS "n_delivered!",
+            (
+                SELECT count(*)
+                FROM issue_opens
+                WHERE newsletter_issue_id = $1
+            ) AS "n_opened!"
+        FROM newsletter_issues i
+        WHERE i.newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_link_clicks(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Vec<LinkClicks>, sqlx::Error> {
+    sqlx::query_as!(
+        LinkClicks,
+        r#"
+        SELECT
+            url,
+            count(*) AS "n_clicks!",
+            count(DISTINCT subscriber_email) AS "n_clickers!"
+        FROM link_clicks
+        WHERE newsletter_issue_id = $1
+        GROUP BY url
+        ORDER BY count(*) DESC, url
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_all(pool)
+    .await
+}
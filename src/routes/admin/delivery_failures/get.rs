@@ -0,0 +1,112 @@
+use crate::routes::e500;
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::fmt::Write;
+use uuid::Uuid;
+
+struct DeliveryFailure {
+    failure_id: Uuid,
+    newsletter_issue_id: Uuid,
+    title: String,
+    subscriber_email: String,
+    n_attempts: i16,
+    last_error: String,
+    provider_response: Option<String>,
+    failed_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Get delivery failures", skip_all)]
+#[get("/delivery-failures")]
+pub async fn list_delivery_failures(
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", m.content()).unwrap();
+    }
+    let failures = get_delivery_failures(&pool).await.map_err(e500)?;
+
+    let mut failures_html = String::new();
+    let mut current_issue = None;
+    for failure in &failures {
+        if current_issue != Some(failure.newsletter_issue_id) {
+            if current_issue.is_some() {
+                failures_html.push_str("        </table>\n");
+            }
+            current_issue = Some(failure.newsletter_issue_id);
+            writeln!(
+                failures_html,
+                r#"        <h2>{}</h2>
+        <table>
+            <tr><th></th><th>Subscriber</th><th>Attempts</th><th>Last error</th><th>Provider response</th><th>Failed at</th></tr>"#,
+                htmlescape::encode_minimal(&failure.title)
+            )
+            .unwrap();
+        }
+        writeln!(
+            failures_html,
+            r#"            <tr><td><input type="checkbox" name="failure_id" value="{}"></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>"#,
+            failure.failure_id,
+            htmlescape::encode_minimal(&failure.subscriber_email),
+            failure.n_attempts,
+            htmlescape::encode_minimal(&failure.last_error),
+            htmlescape::encode_minimal(failure.provider_response.as_deref().unwrap_or("")),
+            failure.failed_at.to_rfc3339(),
+        )
+        .unwrap();
+    }
+    if current_issue.is_some() {
+        failures_html.push_str("        </table>\n");
+    } else {
+        failures_html.push_str("        <p>There are no failed deliveries.</p>\n");
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Delivery failures</title>
+</head>
+<body>
+    {msg_html}
+    <form action="/admin/delivery-failures/retry" method="post">
+{failures_html}        <button type="submit">Retry selected</button>
+    </form>
+    <form action="/admin/delivery-failures/retry-all" method="post">
+        <button type="submit">Retry all</button>
+    </form>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_delivery_failures(pool: &PgPool) -> Result<Vec<DeliveryFailure>, sqlx::Error> {
+    sqlx::query_as!(
+        DeliveryFailure,
+        r#"
+        SELECT
+            f.failure_id,
+            f.newsletter_issue_id,
+            i.title,
+            f.subscriber_email,
+            f.n_attempts,
+            f.last_error,
+            f.provider_response,
+            f.failed_at
+        FROM issue_delivery_failures f
+        JOIN newsletter_issues i ON i.newsletter_issue_id = f.newsletter_issue_id
+        ORDER BY i.published_at DESC, f.newsletter_issue_id, f.subscriber_email
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,5 @@
+mod get;
+mod post;
+
+pub use get::list_delivery_failures;
+pub use post::{retry_all_delivery_failures, retry_delivery_failures};
@@ -0,0 +1,78 @@
+use crate::issue_delivery_worker::notify_delivery_workers;
+use crate::routes::e500;
+use crate::utils::e400;
+use actix_web::{post, web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use reqwest::header::LOCATION;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+fn see_other(route: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, route))
+        .finish()
+}
+
+// Checkboxes submit one `failure_id` pair per ticked row, which a struct
+// cannot capture, so the form is read as a list of key/value pairs.
+#[tracing::instrument(name = "Retry selected delivery failures", skip_all)]
+#[post("/delivery-failures/retry")]
+pub async fn retry_delivery_failures(
+    form: web::Form<Vec<(String, String)>>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let failure_ids = form
+        .0
+        .into_iter()
+        .filter(|(key, _)| key == "failure_id")
+        .map(|(_, value)| Uuid::parse_str(&value))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid failure id")
+        .map_err(e400)?;
+    let n_requeued = requeue_failures(&pool, Some(&failure_ids))
+        .await
+        .map_err(e500)?;
+    FlashMessage::info(format!("{n_requeued} deliveries have been queued again.")).send();
+    Ok(see_other("/admin/delivery-failures"))
+}
+
+#[tracing::instrument(name = "Retry all delivery failures", skip_all)]
+#[post("/delivery-failures/retry-all")]
+pub async fn retry_all_delivery_failures(
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let n_requeued = requeue_failures(&pool, None).await.map_err(e500)?;
+    FlashMessage::info(format!("{n_requeued} deliveries have been queued again.")).send();
+    Ok(see_other("/admin/delivery-failures"))
+}
+
+/// Moves the given failures, or all of them when `failure_ids` is `None`,
+/// back into `issue_delivery_queue` with a fresh retry budget.
+/// Subscribers who unsubscribed, bounced or complained since are left out.
+#[tracing::instrument(skip(pool))]
+async fn requeue_failures(pool: &PgPool, failure_ids: Option<&[Uuid]>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        WITH requeued AS (
+            DELETE FROM issue_delivery_failures
+            USING subscriptions
+            WHERE
+                ($1::uuid[] IS NULL OR failure_id = ANY($1)) AND
+                subscriptions.email = issue_delivery_failures.subscriber_email AND
+                subscriptions.status = 'confirmed'
+            RETURNING newsletter_issue_id, subscriber_email
+        )
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+        SELECT newsletter_issue_id, subscriber_email FROM requeued
+        ON CONFLICT DO NOTHING
+        "#,
+        failure_ids as Option<&[Uuid]>
+    )
+    .execute(pool)
+    .await?;
+    if result.rows_affected() > 0 {
+        notify_delivery_workers(pool).await?;
+    }
+    Ok(result.rows_affected())
+}
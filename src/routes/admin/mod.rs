@@ -1,9 +1,15 @@
 mod dashboard;
+mod deliveries;
+mod delivery_failures;
 mod logout;
 mod newsletter;
 mod password;
+mod suppressions;
 
 pub use dashboard::{admin_dashboard, e500};
+pub use deliveries::*;
+pub use delivery_failures::*;
 pub use logout::*;
 pub use newsletter::*;
 pub use password::*;
+pub use suppressions::*;
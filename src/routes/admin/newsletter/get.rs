@@ -51,6 +51,15 @@ pub async fn get_news_letter_form(
             ></textarea>
         </label>
         <br>
+        <label>
+            <input type="checkbox" name="track_opens" value="true">
+            Track opens
+        </label>
+        <label>
+            <input type="checkbox" name="track_clicks" value="true">
+            Track clicks
+        </label>
+        <br>
         <input hidden type="text" name="idempotency_key" value="{idempotency_key}">
         <button type="submit">Publish</button>
     </form>
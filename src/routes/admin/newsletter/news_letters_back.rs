@@ -1,5 +1,5 @@
 // use crate::{
-//     authentication::{validate_credentials, AuthError, Credentials},
+//     authentication::{basic_authentication, validate_credentials, AuthError},
 //     domains::SubscriberEmail,
 //     email_client::EmailClient,
 // };
@@ -126,39 +126,3 @@
 //     .map(|row| SubscriberEmail::parse(row.email.clone()))
 //     .collect())
 // }
-
-// pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
-//     let header = headers
-//         .get("Authorization")
-//         .context("The 'Authorization header was missing'")?
-//         .to_str()
-//         .context("The Authorization header was not a valid string")?;
-
-//     let encoded_segment = header
-//         .strip_prefix("Basic ")
-//         .context("Authorization header did not match")?;
-
-//     let decoded_bytes = &general_purpose::STANDARD
-//         .decode(encoded_segment)
-//         .context("Authorization data not encoded properly")?;
-
-//     let decoded_credentials =
-//         String::from_utf8(decoded_bytes.to_owned()).context("Unable to decode properly")?;
-
-//     let mut credentials = decoded_credentials.splitn(2, ":");
-
-//     let username = credentials
-//         .next()
-//         .ok_or_else(|| anyhow::anyhow!(" A username must be provided in 'Basic' auth"))?
-//         .to_string();
-
-//     let password = credentials
-//         .next()
-//         .ok_or_else(|| anyhow::anyhow!(" A password must be provided "))?
-//         .to_string();
-
-//     Ok(Credentials {
-//         username,
-//         password: Secret::new(password),
-//     })
-// }
@@ -1,4 +1,5 @@
 use crate::idempotency::{get_saved_response, try_processing, NextAction};
+use crate::issue_delivery_worker::notify_delivery_workers;
 use crate::routes::e500;
 use crate::{
     authentication::UserId, domains::SubscriberEmail, email_client::EmailClient,
@@ -64,6 +65,11 @@ pub struct FormData {
     text_content: String,
     html_content: String,
     idempotency_key: String,
+    /// Checkboxes are only submitted when ticked.
+    #[serde(default)]
+    track_opens: bool,
+    #[serde(default)]
+    track_clicks: bool,
 }
 
 #[tracing::instrument(
@@ -84,6 +90,8 @@ pub async fn publish_newsletter(
         text_content,
         html_content,
         idempotency_key,
+        track_opens,
+        track_clicks,
     } = form.0;
 
     let idempotency_key: IdempotencyKey = idempotency_key
@@ -119,10 +127,17 @@ pub async fn publish_newsletter(
     //         }
     //     }
     // }
-    let issue_id = insert_newsletter_issue(&mut transaction, &title, &text_content, &html_content)
-        .await
-        .context("Failed to store newsletter issue details")
-        .map_err(e500)?;
+    let issue_id = insert_newsletter_issue(
+        &mut transaction,
+        &title,
+        &text_content,
+        &html_content,
+        track_opens,
+        track_clicks,
+    )
+    .await
+    .context("Failed to store newsletter issue details")
+    .map_err(e500)?;
 
     enqueue_delivery_tasks(&mut transaction, issue_id)
         .await
@@ -162,6 +177,8 @@ async fn insert_newsletter_issue(
     title: &str,
     text_content: &str,
     html_content: &str,
+    track_opens: bool,
+    track_clicks: bool,
 ) -> Result<Uuid, sqlx::Error> {
     let newsletter_issue_id = Uuid::new_v4();
     sqlx::query!(
@@ -171,14 +188,18 @@ async fn insert_newsletter_issue(
             title,
             text_content,
             html_content,
+            track_opens,
+            track_clicks,
             published_at
         )
-        VALUES ($1, $2, $3, $4, now())
+        VALUES ($1, $2, $3, $4, $5, $6, now())
         "#,
         newsletter_issue_id,
         title,
         text_content,
-        html_content
+        html_content,
+        track_opens,
+        track_clicks
     )
     .execute(transaction)
     .await?;
@@ -198,11 +219,13 @@ async fn enqueue_delivery_tasks(
         )
         SELECT $1, email
         FROM subscriptions
+        -- Leaves out unsubscribed, bounced, complained and suppressed addresses.
         WHERE status = 'confirmed'
         "#,
         newsletter_issue_id,
     )
-    .execute(transaction)
+    .execute(&mut *transaction)
     .await?;
+    notify_delivery_workers(transaction).await?;
     Ok(())
 }
@@ -0,0 +1,89 @@
+use crate::routes::e500;
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::fmt::Write;
+
+struct Suppression {
+    email: String,
+    reason: String,
+    source: String,
+    created_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Get suppressions", skip_all)]
+#[get("/suppressions")]
+pub async fn list_suppressions(
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", m.content()).unwrap();
+    }
+    let suppressions = get_suppressions(&pool).await.map_err(e500)?;
+
+    let mut suppressions_html = String::new();
+    if suppressions.is_empty() {
+        suppressions_html.push_str("    <p>No address is suppressed.</p>\n");
+    } else {
+        suppressions_html.push_str(
+            "    <table>\n        <tr><th>Email</th><th>Reason</th><th>Source</th><th>Added at</th><th></th></tr>\n",
+        );
+        for suppression in &suppressions {
+            let email = htmlescape::encode_minimal(&suppression.email);
+            writeln!(
+                suppressions_html,
+                r#"        <tr><td>{email}</td><td>{}</td><td>{}</td><td>{}</td><td><form action="/admin/suppressions/remove" method="post"><input type="hidden" name="email" value="{}"><button type="submit">Remove</button></form></td></tr>"#,
+                htmlescape::encode_minimal(&suppression.reason),
+                htmlescape::encode_minimal(&suppression.source),
+                suppression.created_at.to_rfc3339(),
+                htmlescape::encode_attribute(&suppression.email),
+            )
+            .unwrap();
+        }
+        suppressions_html.push_str("    </table>\n");
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Suppressions</title>
+</head>
+<body>
+    {msg_html}
+    <p>Nothing is ever sent to these addresses.</p>
+{suppressions_html}    <form action="/admin/suppressions" method="post">
+        <label>Email
+            <input type="email" placeholder="Enter the address" name="email">
+        </label>
+        <label>Reason
+            <input type="text" placeholder="Why?" name="reason">
+        </label>
+        <button type="submit">Suppress</button>
+    </form>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_suppressions(pool: &PgPool) -> Result<Vec<Suppression>, sqlx::Error> {
+    sqlx::query_as!(
+        Suppression,
+        r#"
+        SELECT email, reason, source, created_at
+        FROM suppressions
+        ORDER BY created_at DESC, email
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
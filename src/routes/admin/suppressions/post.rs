@@ -0,0 +1,66 @@
+use crate::domains::SubscriberEmail;
+use crate::routes::e500;
+use crate::suppressions;
+use crate::utils::e400;
+use actix_web::{post, web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use reqwest::header::LOCATION;
+use sqlx::PgPool;
+
+fn see_other(route: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, route))
+        .finish()
+}
+
+#[derive(serde::Deserialize)]
+pub struct AddSuppressionData {
+    email: String,
+    reason: String,
+}
+
+#[tracing::instrument(name = "Add a suppression", skip_all, fields(email = %form.email))]
+#[post("/suppressions")]
+pub async fn add_suppression(
+    form: web::Form<AddSuppressionData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let email = SubscriberEmail::parse(suppressions::normalise_email(&form.email)).map_err(e400)?;
+    let reason = match form.reason.trim() {
+        "" => "manual",
+        reason => reason,
+    };
+    let added = suppressions::suppress(&**pool, email.as_ref(), reason, suppressions::SOURCE_ADMIN)
+        .await
+        .map_err(e500)?;
+    if added {
+        FlashMessage::info(format!("{} has been suppressed.", email.as_ref())).send();
+    } else {
+        FlashMessage::info(format!("{} was already suppressed.", email.as_ref())).send();
+    }
+    Ok(see_other("/admin/suppressions"))
+}
+
+#[derive(serde::Deserialize)]
+pub struct RemoveSuppressionData {
+    email: String,
+}
+
+#[tracing::instrument(name = "Remove a suppression", skip_all, fields(email = %form.email))]
+#[post("/suppressions/remove")]
+pub async fn remove_suppression(
+    form: web::Form<RemoveSuppressionData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let removed = suppressions::unsuppress(&**pool, &form.email)
+        .await
+        .map_err(e500)?;
+    if removed {
+        FlashMessage::info(format!(
+            "{} can receive emails again.",
+            suppressions::normalise_email(&form.email)
+        ))
+        .send();
+    }
+    Ok(see_other("/admin/suppressions"))
+}
@@ -1,7 +1,8 @@
 use crate::{
     domains::{NewSubscriber, SubscriberEmail, SubscriberName},
-    email_client::EmailClient,
-    startup::ApplicationBaseUrl,
+    email_outbox_worker::enqueue_email,
+    startup::{ApplicationBaseUrl, SubscriptionTokenTtl},
+    suppressions,
 };
 use actix_web::{post, web, HttpResponse, ResponseError};
 use chrono::Utc;
@@ -95,7 +96,7 @@ impl TryFrom<FormData> for NewSubscriber {
     }
 }
 
-fn generate_subscription_token() -> String {
+pub fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
@@ -105,30 +106,89 @@ fn generate_subscription_token() -> String {
 
 // Spans, like logs, have an associated level // `info_span` creates a span at the info-level
 // See the following section on `Instrumenting Futures`
-#[tracing::instrument(name = "Adding a new subscriber.", skip(form, pool,  email_client, base_url), fields(subscriber_email = %form.email, subscriber_name= %form.name))]
+#[tracing::instrument(name = "Adding a new subscriber.", skip(form, pool, base_url, token_ttl), fields(subscriber_email = %form.email, subscriber_name= %form.name))]
 #[post("/subscriptions")]
 async fn subscriptions(
     form: web::Form<FormData>,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
     base_url: web::Data<ApplicationBaseUrl>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
 ) -> Result<HttpResponse, SubscribeError> {
-    let new_subscriber = form.0.try_into().map_err(SubscribeError::ValidationError)?;
-    let subscription_token = generate_subscription_token();
+    let new_subscriber: NewSubscriber =
+        form.0.try_into().map_err(SubscribeError::ValidationError)?;
     let mut transaction = pool
         .begin()
         .await
         .context("Failed to acquire a Postgres connection from the pool")?;
 
-    let subscription_id = insert(&new_subscriber, &mut transaction)
+    // A confirmation email to a suppressed address would bounce or be reported as spam again.
+    if suppressions::is_suppressed(&mut transaction, new_subscriber.email.as_ref())
         .await
-        .context("Unable to inert subscriber into database")?;
-    // .map_err(SubscribeError::InsertSubscriberErrors)?;
-
-    store_token(&subscription_id, &subscription_token, &mut transaction)
+        .context("Unable to check the suppression list")?
+    {
+        tracing::info!("Subscriber address is suppressed, not sending a confirmation email");
+        return Ok(HttpResponse::Ok().finish());
+    }
+    // Submitting the form again must look exactly like the first submission,
+    // so the response never reveals whether the address was already known.
+    let subscription_token = match get_existing_subscription(&new_subscriber, &mut transaction)
         .await
-        .context("Unable to store token for subscriber")?;
-    // .map_err(SubscribeError::StoreTokenError)?;
+        .context("Unable to look up an existing subscription")?
+    {
+        Some((_, status)) if status == "confirmed" => {
+            tracing::info!("Subscriber is already confirmed, nothing to do");
+            return Ok(HttpResponse::Ok().finish());
+        }
+        // Anyone else, e.g. after unsubscribing, must confirm the subscription again.
+        Some((subscription_id, status)) => {
+            tracing::info!(%status, "Asking an existing subscriber to confirm again");
+            mark_pending_confirmation(&subscription_id, &mut transaction)
+                .await
+                .context("Unable to reset the subscription status")?;
+            match get_valid_token(&subscription_id, &token_ttl.0, &mut transaction)
+                .await
+                .context("Unable to fetch token for subscriber")?
+            {
+                Some(subscription_token) => subscription_token,
+                None => {
+                    let subscription_token = generate_subscription_token();
+                    store_token(&subscription_id, &subscription_token, &mut transaction)
+                        .await
+                        .context("Unable to store token for subscriber")?;
+                    subscription_token
+                }
+            }
+        }
+        None => {
+            let subscription_id = match insert(&new_subscriber, &mut transaction)
+                .await
+                .context("Unable to inert subscriber into database")?
+            {
+                Some(subscription_id) => subscription_id,
+                // A concurrent request inserted the same address first; it sends the email.
+                None => return Ok(HttpResponse::Ok().finish()),
+            };
+            // .map_err(SubscribeError::InsertSubscriberErrors)?;
+
+            let subscription_token = generate_subscription_token();
+            store_token(&subscription_id, &subscription_token, &mut transaction)
+                .await
+                .context("Unable to store token for subscriber")?;
+            // .map_err(SubscribeError::StoreTokenError)?;
+            subscription_token
+        }
+    };
+
+    // The email goes out through the outbox, so an email provider outage
+    // cannot leave a committed subscriber without a confirmation email.
+    enqueue_confirmation_email(
+        &mut transaction,
+        &new_subscriber.email,
+        &base_url.0,
+        &subscription_token,
+    )
+    .await
+    .context("Failed to enqueue confirmation Email to customer")?;
 
     transaction
         .commit()
@@ -136,28 +196,20 @@ async fn subscriptions(
         .context("Unable to save transaction")?;
 
     tracing::info!("New subscriber details have been saved");
-    send_confirmation_email_to_customer(
-        &email_client,
-        new_subscriber,
-        &base_url.0,
-        &subscription_token,
-    )
-    .await
-    .context("Failed to send confirmation Email to customer")?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
 #[tracing::instrument(
-    name = "Sending confirmation email to customer",
-    skip(email_client, new_subscriber)
+    name = "Enqueueing confirmation email to customer",
+    skip(transaction, recipient)
 )]
-async fn send_confirmation_email_to_customer(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
+pub async fn enqueue_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
     base_url: &String,
     subscription_token: &String,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), sqlx::Error> {
     let confirmation_link =
         &format!("{base_url}/subscriptions/confirm?subscription_token={subscription_token}");
     let plain_body = &format!(
@@ -169,9 +221,63 @@ async fn send_confirmation_email_to_customer(
 		Click <a href=\"{}\">here</a> to confirm your subscription.",
         confirmation_link
     );
-    email_client
-        .send_email(&new_subscriber.email, "Welcome!", &plain_body, html_body)
-        .await
+    enqueue_email(transaction, recipient, "Welcome!", html_body, plain_body).await
+}
+
+#[tracing::instrument(
+    name = "Looking up an existing subscription",
+    skip(new_subscriber, transaction)
+)]
+pub async fn get_existing_subscription(
+    new_subscriber: &NewSubscriber,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT id, status FROM subscriptions WHERE email = $1 FOR UPDATE"#,
+        new_subscriber.email.as_ref()
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(result.map(|r| (r.id, r.status)))
+}
+
+#[tracing::instrument(
+    name = "Resetting subscription to pending confirmation",
+    skip(transaction)
+)]
+pub async fn mark_pending_confirmation(
+    subscription_id: &Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'pending_confirmation' WHERE id = $1"#,
+        subscription_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Retrieving a valid subscription token of a subscriber",
+    skip(transaction)
+)]
+pub async fn get_valid_token(
+    subscription_id: &Uuid,
+    token_ttl: &chrono::Duration,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<String>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT subscription_token FROM subscription_tokens
+        WHERE subscription_id = $1 AND created_at > $2
+        ORDER BY created_at DESC
+        LIMIT 1"#,
+        subscription_id,
+        Utc::now() - *token_ttl
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(result.map(|r| r.subscription_token))
 }
 
 #[tracing::instrument(
@@ -181,11 +287,12 @@ async fn send_confirmation_email_to_customer(
 pub async fn insert(
     new_subscriber: &NewSubscriber,
     transaction: &mut Transaction<'_, Postgres>,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Option<Uuid>, sqlx::Error> {
     let subscription_id = Uuid::new_v4();
-    sqlx::query!(
+    let n_inserted_rows = sqlx::query!(
         r#"
    INSERT INTO subscriptions (id, email, name, subscribed_at, status) VALUES ($1, $2, $3, $4, 'pending_confirmation')
+   ON CONFLICT (email) DO NOTHING
    "#,
    subscription_id,
         new_subscriber.email.as_ref(),
@@ -195,9 +302,10 @@ pub async fn insert(
     // We use `get_ref` to get an immutable reference to the `PgConnection`
     // wrapped by `web::Data`.
     .execute(transaction)
-    .await?;
+    .await?
+    .rows_affected();
 
-    Ok(subscription_id)
+    Ok((n_inserted_rows > 0).then_some(subscription_id))
 }
 
 #[tracing::instrument(
@@ -1,4 +1,17 @@
-use actix_web::{get, web, HttpResponse};
+use crate::{
+    domains::SubscriberEmail,
+    routes::{e500, enqueue_confirmation_email, generate_subscription_token, store_token},
+    startup::{ApplicationBaseUrl, SubscriptionTokenTtl},
+    suppressions,
+};
+use actix_web::{
+    get,
+    http::header::{ContentType, ACCEPT},
+    http::StatusCode,
+    post, web, HttpRequest, HttpResponse,
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -7,28 +20,272 @@ pub struct Parameters {
     subscription_token: String,
 }
 
-#[tracing::instrument(name = "Confirming a subscriber", skip(_parameters))]
-#[get("/subscriptions/confirm")]
-pub async fn confirm(_parameters: web::Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let subscription_id =
-        match get_subscription_id_from_token(&pool, &_parameters.subscription_token).await {
-            Ok(subscription_id) => subscription_id,
-            Err(_) => return HttpResponse::InternalServerError().finish(),
+pub enum ConfirmationOutcome {
+    Pending(String),
+    Confirmed,
+    AlreadyConfirmed,
+    Expired(String),
+    /// Unsubscribed, bounced or complained since the link was sent: it must not re-activate them.
+    NoLongerPending,
+    UnknownToken,
+}
+
+impl ConfirmationOutcome {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmationOutcome::Pending(_)
+            | ConfirmationOutcome::Confirmed
+            | ConfirmationOutcome::AlreadyConfirmed => StatusCode::OK,
+            ConfirmationOutcome::Expired(_) | ConfirmationOutcome::NoLongerPending => {
+                StatusCode::GONE
+            }
+            ConfirmationOutcome::UnknownToken => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConfirmationOutcome::Pending(_) => "pending_confirmation",
+            ConfirmationOutcome::Confirmed => "confirmed",
+            ConfirmationOutcome::AlreadyConfirmed => "already_confirmed",
+            ConfirmationOutcome::Expired(_) => "expired",
+            ConfirmationOutcome::NoLongerPending => "no_longer_pending",
+            ConfirmationOutcome::UnknownToken => "unknown_token",
+        }
+    }
+
+    fn html(&self) -> String {
+        let (title, body) = match self {
+            ConfirmationOutcome::Pending(subscription_token) => (
+                "Confirm your subscription",
+                format!(
+                    r#"<p>Please confirm that you want to receive our newsletter.</p>
+    <form action="/subscriptions/confirm" method="post">
+        <input hidden type="text" name="subscription_token" value="{}">
+        <button type="submit">Confirm my subscription</button>
+    </form>"#,
+                    htmlescape::encode_attribute(subscription_token)
+                ),
+            ),
+            ConfirmationOutcome::Confirmed => (
+                "You're confirmed",
+                "<p>You're confirmed! The next issue will land in your inbox.</p>".to_string(),
+            ),
+            ConfirmationOutcome::AlreadyConfirmed => (
+                "Already confirmed",
+                "<p>Your subscription has already been confirmed. There is nothing else to do.</p>"
+                    .to_string(),
+            ),
+            ConfirmationOutcome::Expired(subscription_token) => (
+                "Confirmation link expired",
+                format!(
+                    r#"<p>This confirmation link has expired.</p>
+    <form action="/subscriptions/confirm/resend" method="post">
+        <input hidden type="text" name="subscription_token" value="{}">
+        <button type="submit">Send me a new confirmation email</button>
+    </form>"#,
+                    htmlescape::encode_attribute(subscription_token)
+                ),
+            ),
+            ConfirmationOutcome::NoLongerPending => (
+                "Confirmation link no longer valid",
+                "<p>This confirmation link is no longer valid. Subscribe again to receive our newsletter.</p>"
+                    .to_string(),
+            ),
+            ConfirmationOutcome::UnknownToken => (
+                "Invalid confirmation link",
+                "<p>We could not find a subscription for this confirmation link.</p>".to_string(),
+            ),
         };
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>{title}</title>
+</head>
+<body>
+    {body}
+</body>
+</html>"#
+        )
+    }
+
+    /// Renders the outcome as JSON when the client asks for it, as an HTML page otherwise.
+    pub fn respond_to(&self, request: &HttpRequest) -> HttpResponse {
+        let wants_json = request
+            .headers()
+            .get(ACCEPT)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|h| h.contains("application/json"));
+        let mut response = HttpResponse::build(self.status_code());
+        if wants_json {
+            response.json(serde_json::json!({ "status": self.as_str() }))
+        } else {
+            response.content_type(ContentType::html()).body(self.html())
+        }
+    }
+}
+
+/// Returns the subscription id if the token can still be used to confirm,
+/// otherwise the outcome to show to the subscriber.
+fn check_token(
+    token: Option<StoredToken>,
+    subscription_token: &str,
+    token_ttl: &chrono::Duration,
+) -> Result<Uuid, ConfirmationOutcome> {
+    match token {
+        None => Err(ConfirmationOutcome::UnknownToken),
+        Some(token) if token.status == "confirmed" => Err(ConfirmationOutcome::AlreadyConfirmed),
+        Some(token) if token.status != "pending_confirmation" => {
+            Err(ConfirmationOutcome::NoLongerPending)
+        }
+        Some(token) if token.created_at + *token_ttl < Utc::now() => {
+            Err(ConfirmationOutcome::Expired(subscription_token.to_owned()))
+        }
+        Some(token) => Ok(token.subscription_id),
+    }
+}
+
+// Link scanners prefetch every URL in an email, so following the link only
+// renders a button; the subscription is confirmed by the POST it submits.
+#[tracing::instrument(
+    name = "Showing the confirmation page",
+    skip(_parameters, token_ttl, request)
+)]
+#[get("/subscriptions/confirm")]
+pub async fn confirm_form(
+    _parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let token = get_subscription_id_from_token(&pool, &_parameters.subscription_token)
+        .await
+        .map_err(e500)?;
+    let outcome = match check_token(token, &_parameters.subscription_token, &token_ttl.0) {
+        Ok(_) => ConfirmationOutcome::Pending(_parameters.0.subscription_token),
+        Err(outcome) => outcome,
+    };
+
+    Ok(outcome.respond_to(&request))
+}
 
-    match subscription_id {
-        None => return HttpResponse::InternalServerError().finish(),
-        Some(subscription_id) => {
-            if confirm_subscription_token(&pool, &subscription_id)
+#[tracing::instrument(name = "Confirming a subscriber", skip(form, token_ttl, request))]
+#[post("/subscriptions/confirm")]
+pub async fn confirm(
+    form: web::Form<Parameters>,
+    pool: web::Data<PgPool>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let token = get_subscription_id_from_token(&pool, &form.subscription_token)
+        .await
+        .map_err(e500)?;
+    let outcome = match check_token(token, &form.subscription_token, &token_ttl.0) {
+        Ok(subscription_id) => {
+            confirm_subscription_token(&pool, &subscription_id)
                 .await
-                .is_err()
-            {
-                return HttpResponse::InternalServerError().finish();
-            }
+                .map_err(e500)?;
+            ConfirmationOutcome::Confirmed
         }
+        Err(outcome) => outcome,
+    };
+
+    Ok(outcome.respond_to(&request))
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResendFormData {
+    subscription_token: String,
+}
+
+// The response is the same whether or not the token was known,
+// so the form cannot be used to probe for subscribers.
+#[tracing::instrument(name = "Resending a confirmation email", skip(form, pool, base_url))]
+#[post("/subscriptions/confirm/resend")]
+pub async fn resend_confirmation(
+    form: web::Form<ResendFormData>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, actix_web::Error> {
+    rotate_token(&pool, &form.subscription_token, &base_url.0)
+        .await
+        .map_err(e500)?;
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Confirmation email sent</title>
+</head>
+<body>
+    <p>If the subscription is still waiting for confirmation, a new link is on its way.</p>
+</body>
+</html>"#,
+    ))
+}
+
+/// Replaces every token of a pending subscription with a fresh one and queues a new confirmation email.
+/// Does nothing if the token is unknown or the subscription is no longer pending.
+#[tracing::instrument(
+    name = "Rotating a subscription token",
+    skip(pool, subscription_token, base_url)
+)]
+async fn rotate_token(
+    pool: &PgPool,
+    subscription_token: &str,
+    base_url: &String,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscription = sqlx::query!(
+        r#"SELECT s.id, s.email FROM subscriptions s
+        JOIN subscription_tokens t ON t.subscription_id = s.id
+        WHERE t.subscription_token = $1 AND s.status = 'pending_confirmation'
+        FOR UPDATE OF s"#,
+        subscription_token
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look up the subscription")?;
+    let subscription = match subscription {
+        Some(subscription) => subscription,
+        None => return Ok(()),
+    };
+    // The address may have bounced or complained since it subscribed.
+    if suppressions::is_suppressed(&mut transaction, &subscription.email)
+        .await
+        .context("Unable to check the suppression list")?
+    {
+        tracing::info!("Subscriber address is suppressed, not sending a new confirmation email");
+        return Ok(());
     }
 
-    HttpResponse::Ok().finish()
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscription_id = $1"#,
+        subscription.id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete old subscription tokens")?;
+    let new_token = generate_subscription_token();
+    store_token(&subscription.id, &new_token, &mut transaction)
+        .await
+        .context("Unable to store token for subscriber")?;
+    let email = SubscriberEmail::parse(subscription.email).map_err(anyhow::Error::msg)?;
+    enqueue_confirmation_email(&mut transaction, &email, base_url, &new_token)
+        .await
+        .context("Failed to enqueue confirmation Email to customer")?;
+    transaction
+        .commit()
+        .await
+        .context("Unable to save transaction")?;
+
+    Ok(())
 }
 
 #[tracing::instrument(name = "Confirming a subscription in the database", skip(pool))]
@@ -37,7 +294,7 @@ pub async fn confirm_subscription_token(
     subscription_id: &Uuid,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
+        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1 AND status = 'pending_confirmation'"#,
         subscription_id
     )
     .execute(pool)
@@ -49,6 +306,12 @@ pub async fn confirm_subscription_token(
     Ok(())
 }
 
+pub struct StoredToken {
+    pub subscription_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub status: String,
+}
+
 #[tracing::instrument(
     name = "Retrieving a subscription token from database",
     skip(pool, subscription_token)
@@ -56,9 +319,13 @@ pub async fn confirm_subscription_token(
 pub async fn get_subscription_id_from_token(
     pool: &PgPool,
     subscription_token: &String,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscription_id from subscription_tokens WHERE subscription_token = $1"#,
+) -> Result<Option<StoredToken>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        StoredToken,
+        r#"SELECT t.subscription_id, t.created_at, s.status
+        FROM subscription_tokens t
+        JOIN subscriptions s ON s.id = t.subscription_id
+        WHERE t.subscription_token = $1"#,
         subscription_token
     )
     .fetch_optional(pool)
@@ -67,5 +334,5 @@ pub async fn get_subscription_id_from_token(
         tracing::error!("Failed to execute query: {:?}", err);
         err
     })?;
-    return Ok(result.map(|r| r.subscription_id));
+    return Ok(result);
 }
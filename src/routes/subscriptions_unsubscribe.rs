@@ -0,0 +1,158 @@
+use crate::startup::HmacSecret;
+use actix_web::{get, http::header::ContentType, post, web, HttpResponse};
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::e500;
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    subscription_id: Uuid,
+    token: String,
+}
+
+impl UnsubscribeParameters {
+    fn verify(&self, secret: &HmacSecret) -> Result<Uuid, anyhow::Error> {
+        let tag = hex::decode(&self.token)?;
+        let mac = unsubscribe_mac(&secret.0, &self.subscription_id);
+        mac.verify_slice(&tag)?;
+        Ok(self.subscription_id)
+    }
+}
+
+fn unsubscribe_mac(secret: &Secret<String>, subscription_id: &Uuid) -> Hmac<sha2::Sha256> {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.expose_secret().as_bytes()).unwrap();
+    mac.update(format!("unsubscribe={subscription_id}").as_bytes());
+    mac
+}
+
+/// Builds the signed link a subscriber can use to leave the list.
+/// The same URL serves the confirmation page (GET) and the RFC 8058 one-click endpoint (POST).
+pub fn unsubscribe_link(base_url: &str, secret: &Secret<String>, subscription_id: &Uuid) -> String {
+    let token = unsubscribe_mac(secret, subscription_id)
+        .finalize()
+        .into_bytes();
+    format!(
+        "{base_url}/subscriptions/unsubscribe?subscription_id={subscription_id}&token={token:x}"
+    )
+}
+
+#[tracing::instrument(name = "Show unsubscribe page", skip(parameters, secret))]
+#[get("/subscriptions/unsubscribe")]
+pub async fn unsubscribe_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    secret: web::Data<HmacSecret>,
+) -> HttpResponse {
+    if parameters.verify(&secret).is_err() {
+        return invalid_link();
+    }
+    let action = format!(
+        "/subscriptions/unsubscribe?subscription_id={}&token={}",
+        parameters.subscription_id, parameters.token
+    );
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>Do you want to stop receiving our newsletter?</p>
+    <form action="{action}" method="post">
+        <input hidden type="text" name="List-Unsubscribe" value="One-Click">
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#
+        ))
+}
+
+// Mailbox providers POST `List-Unsubscribe=One-Click` to the link from the
+// `List-Unsubscribe` header (RFC 8058); the body carries no information we need.
+#[tracing::instrument(name = "Unsubscribing a subscriber", skip(parameters, pool, secret))]
+#[post("/subscriptions/unsubscribe")]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subscription_id = match parameters.verify(&secret) {
+        Ok(subscription_id) => subscription_id,
+        Err(_) => return Ok(invalid_link()),
+    };
+    unsubscribe_subscriber(&pool, &subscription_id)
+        .await
+        .map_err(e500)?;
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribed</title>
+</head>
+<body>
+    <p>You have been unsubscribed. You will not receive any further issues.</p>
+</body>
+</html>"#,
+    ))
+}
+
+fn invalid_link() -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type(ContentType::html())
+        .body(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>This unsubscribe link is invalid.</p>
+</body>
+</html>"#,
+        )
+}
+
+#[tracing::instrument(name = "Marking a subscription as unsubscribed", skip(pool))]
+pub async fn unsubscribe_subscriber(
+    pool: &PgPool,
+    subscription_id: &Uuid,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let email = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1 RETURNING email"#,
+        subscription_id
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to update the subscription status")?
+    .map(|r| r.email);
+
+    // Issues that are still waiting in the queue must not reach them either.
+    if let Some(email) = email {
+        sqlx::query!(
+            r#"DELETE FROM issue_delivery_queue WHERE subscriber_email = $1"#,
+            email
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to drop pending deliveries")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the unsubscribe transaction")?;
+    Ok(())
+}
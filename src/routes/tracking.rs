@@ -0,0 +1,105 @@
+use crate::routes::e500;
+use crate::startup::HmacSecret;
+use crate::tracking::{verify_click_token, verify_open_token, ClickTarget, OPEN_PIXEL};
+use actix_web::http::header::{CacheControl, CacheDirective, LOCATION};
+use actix_web::{get, web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[tracing::instrument(name = "Track an open", skip_all)]
+#[get("/t/o/{token}.gif")]
+pub async fn track_open(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (newsletter_issue_id, subscription_id) = match verify_open_token(&secret.0, &token) {
+        Ok(ids) => ids,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+    record_open(&pool, newsletter_issue_id, subscription_id)
+        .await
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/gif")
+        // Every time the email is displayed should reach us.
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .body(OPEN_PIXEL))
+}
+
+/// Keeps the first and last time the issue was opened; nothing is recorded for a
+/// subscription that no longer exists.
+#[tracing::instrument(skip(pool))]
+async fn record_open(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    subscription_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_opens (
+            newsletter_issue_id,
+            subscriber_email,
+            first_opened_at,
+            last_opened_at,
+            n_opens
+        )
+        SELECT $1, email, now(), now(), 1
+        FROM subscriptions
+        WHERE id = $2
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET
+            last_opened_at = EXCLUDED.last_opened_at,
+            n_opens = issue_opens.n_opens + 1
+        "#,
+        newsletter_issue_id,
+        subscription_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Track a click", skip_all)]
+#[get("/t/c/{token}")]
+pub async fn track_click(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Never redirect on a token we did not sign, or anyone could borrow our domain for phishing.
+    let target = match verify_click_token(&secret.0, &token) {
+        Ok(target) => target,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+    record_click(&pool, &target).await.map_err(e500)?;
+    Ok(HttpResponse::Found()
+        .insert_header((LOCATION, target.url))
+        .finish())
+}
+
+/// The redirect still happens for a subscription that no longer exists; only the click is lost.
+#[tracing::instrument(skip(pool))]
+async fn record_click(pool: &PgPool, target: &ClickTarget) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO link_clicks (
+            click_id,
+            newsletter_issue_id,
+            subscriber_email,
+            url,
+            clicked_at
+        )
+        SELECT $1, $2, email, $3, now()
+        FROM subscriptions
+        WHERE id = $4
+        "#,
+        Uuid::new_v4(),
+        target.newsletter_issue_id,
+        target.url,
+        target.subscription_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use crate::authentication::basic_authentication;
+use crate::routes::error_chain_fmt;
+use crate::startup::WebhookCredentials;
+use crate::suppressions;
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum WebhookError {
+    #[error("Authentication failed")]
+    AuthError(#[source] anyhow::Error),
+    #[error("Invalid webhook payload")]
+    InvalidPayload(#[source] serde_json::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebhookError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            WebhookError::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+            WebhookError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            WebhookError::AuthError(_) => HttpResponse::build(StatusCode::UNAUTHORIZED)
+                .append_header((header::WWW_AUTHENTICATE, r#"Basic realm="webhooks""#))
+                .finish(),
+            _ => HttpResponse::new(self.status_code()),
+        }
+    }
+}
+
+/// The webhooks we act on. Postmark sends bounces and spam complaints with the same fields.
+/// https://postmarkapp.com/developer/webhooks/bounce-webhook
+#[derive(serde::Deserialize)]
+#[serde(tag = "RecordType")]
+enum PostmarkWebhook {
+    Bounce(PostmarkBounce),
+    SpamComplaint(PostmarkBounce),
+    // Deliveries, opens, clicks...
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkBounce {
+    #[serde(rename = "ID")]
+    id: i64,
+    /// E.g. `HardBounce`, `SoftBounce` or `SpamComplaint`.
+    r#type: String,
+    email: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+    details: Option<String>,
+    bounced_at: DateTime<Utc>,
+    /// Whether Postmark stopped sending to the address.
+    #[serde(default)]
+    inactive: bool,
+}
+
+impl PostmarkBounce {
+    /// Soft bounces, e.g. a full mailbox, sort themselves out.
+    fn is_permanent(&self) -> bool {
+        self.inactive || self.r#type == "HardBounce"
+    }
+}
+
+#[tracing::instrument(name = "Receive a Postmark webhook", skip_all)]
+#[post("/webhooks/postmark")]
+pub async fn postmark_webhook(
+    request: HttpRequest,
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+    expected: web::Data<WebhookCredentials>,
+) -> Result<HttpResponse, WebhookError> {
+    let credentials = basic_authentication(request.headers()).map_err(WebhookError::AuthError)?;
+    if !credentials.matches(&expected.0) {
+        return Err(WebhookError::AuthError(anyhow::anyhow!(
+            "Invalid webhook credentials"
+        )));
+    }
+    let (record_type, bounce, status) =
+        match serde_json::from_slice(&body).map_err(WebhookError::InvalidPayload)? {
+            PostmarkWebhook::Bounce(bounce) => ("Bounce", bounce, "bounced"),
+            PostmarkWebhook::SpamComplaint(complaint) => ("SpamComplaint", complaint, "complained"),
+            PostmarkWebhook::Other => return Ok(HttpResponse::Ok().finish()),
+        };
+    tracing::info!(
+        record_type,
+        bounce_type = %bounce.r#type,
+        subscriber_email = %bounce.email,
+        "Received a bounce",
+    );
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let is_new = record_bounce(&mut transaction, record_type, &bounce)
+        .await
+        .context("Failed to record the bounce")?;
+    // Postmark retries webhooks it thinks failed: only the first copy counts.
+    if is_new && (record_type == "SpamComplaint" || bounce.is_permanent()) {
+        mark_subscriber(&mut transaction, &bounce.email, status)
+            .await
+            .context("Failed to update the subscriber's status")?;
+        suppressions::suppress(
+            &mut transaction,
+            &bounce.email,
+            status,
+            suppressions::SOURCE_WEBHOOK,
+        )
+        .await
+        .context("Failed to add the address to the suppression list")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the bounce")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Returns `false` if Postmark already sent us this bounce.
+#[tracing::instrument(skip(transaction, bounce))]
+async fn record_bounce(
+    transaction: &mut Transaction<'_, Postgres>,
+    record_type: &str,
+    bounce: &PostmarkBounce,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO email_bounces (
+            bounce_id,
+            record_type,
+            provider_bounce_id,
+            bounce_type,
+            email,
+            provider_message_id,
+            details,
+            bounced_at,
+            received_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, now())
+        ON CONFLICT (record_type, provider_bounce_id) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        record_type,
+        bounce.id,
+        bounce.r#type,
+        bounce.email,
+        bounce.message_id,
+        bounce.details,
+        bounce.bounced_at,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// A complaint is never overwritten by a later bounce and an unsubscribe stays an unsubscribe.
+#[tracing::instrument(skip(transaction))]
+async fn mark_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+    status: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = $2
+        WHERE
+            lower(email) = lower($1) AND
+            status IN ('confirmed', 'pending_confirmation', 'suppressed')
+        "#,
+        email,
+        status,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
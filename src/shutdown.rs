@@ -0,0 +1,95 @@
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Tells long-running tasks that the process is shutting down: they finish
+/// what they are doing, stop picking up new work and return.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+/// Requests the shutdown of every `Shutdown` handed out by `shutdown_channel`.
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+pub fn shutdown_channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger(sender), Shutdown(receiver))
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+impl Shutdown {
+    /// A shutdown that is never requested, for tasks that are simply dropped when done, e.g. in tests.
+    pub fn never() -> Self {
+        let (_, receiver) = watch::channel(false);
+        Self(receiver)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown has been requested.
+    pub async fn requested(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                // The trigger is gone without ever firing.
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Sleeps for `duration`, or less if shutdown is requested in the meantime.
+    pub async fn sleep(&mut self, duration: Duration) {
+        tokio::select! {
+            _ = self.requested() => {}
+            _ = tokio::time::sleep(duration) => {}
+        }
+    }
+}
+
+/// Resolves when the process receives SIGINT or SIGTERM.
+pub async fn shutdown_signal() -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{shutdown_channel, Shutdown};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn every_clone_sees_the_shutdown_request() {
+        let (trigger, shutdown) = shutdown_channel();
+        let mut other = shutdown.clone();
+        assert!(!shutdown.is_requested());
+
+        trigger.trigger();
+
+        assert!(shutdown.is_requested());
+        tokio::time::timeout(Duration::from_millis(100), other.requested())
+            .await
+            .expect("The shutdown request was not seen");
+    }
+
+    #[tokio::test]
+    async fn a_shutdown_that_is_never_requested_does_not_resolve() {
+        let mut shutdown = Shutdown::never();
+
+        let outcome = tokio::time::timeout(Duration::from_millis(100), shutdown.requested()).await;
+
+        assert!(outcome.is_err());
+    }
+}
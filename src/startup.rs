@@ -1,10 +1,13 @@
-use crate::authentication::reject_anonymous_users;
-use crate::configuration::Settings;
-use crate::email_client::EmailClient;
+use crate::authentication::{reject_anonymous_users, Credentials};
+use crate::configuration::{DeliverySettings, Settings};
 use crate::routes::{
-    admin_dashboard, change_password, change_password_form, confirm, get_news_letter_form,
-    health_check, home, log_out, login, login_form, publish_newsletter, subscriptions,
+    add_suppression, admin_dashboard, change_password, change_password_form, confirm, confirm_form,
+    get_news_letter_form, health_check, home, list_deliveries, list_delivery_failures,
+    list_suppressions, log_out, login, login_form, postmark_webhook, publish_newsletter,
+    remove_suppression, resend_confirmation, retry_all_delivery_failures, retry_delivery_failures,
+    subscriptions, track_click, track_open, unsubscribe, unsubscribe_form,
 };
+use crate::shutdown::Shutdown;
 use actix_session::{storage::RedisSessionStore, SessionMiddleware};
 use actix_web::dev::Server;
 use actix_web::{cookie::Key, web, App, HttpServer};
@@ -26,16 +29,21 @@ pub struct Application {
 impl Application {
     pub async fn build(settings: &Settings) -> Result<Self, anyhow::Error> {
         let connection_pool = get_connection_pool(settings);
-        let email_client = settings.email_client.client();
         let listener = TcpListener::bind(format!("127.0.0.1:{}", settings.application.port))?;
         let port = listener.local_addr().unwrap().port();
         let server = run(
             listener,
             connection_pool,
-            email_client,
             settings.application.base_url.to_owned(),
             settings.application.hmac_secret.to_owned(),
             settings.redis_uri.to_owned(),
+            settings.application.subscription_token_ttl(),
+            settings.application.shutdown_timeout(),
+            settings.delivery.clone(),
+            Credentials {
+                username: settings.email_client.webhook.username.clone(),
+                password: settings.email_client.webhook.password.clone(),
+            },
         )
         .await?;
 
@@ -46,7 +54,14 @@ impl Application {
         self.port
     }
 
-    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+    /// Serves requests until `shutdown` is requested, then stops accepting
+    /// connections and lets the in-flight requests finish.
+    pub async fn run_until_stopped(self, mut shutdown: Shutdown) -> Result<(), std::io::Error> {
+        let handle = self.server.handle();
+        tokio::spawn(async move {
+            shutdown.requested().await;
+            handle.stop(true).await;
+        });
         self.server.await
     }
 }
@@ -61,20 +76,31 @@ pub struct HmacSecret(pub Secret<String>);
 
 pub struct ApplicationBaseUrl(pub String);
 
+pub struct SubscriptionTokenTtl(pub chrono::Duration);
+
+pub struct WebhookCredentials(pub Credentials);
+
 // https://ryhl.io/blog/async-what-is-blocking/
+#[allow(clippy::too_many_arguments)]
 async fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
     base_url: String,
     hmac_secret: Secret<String>,
     redis_uri: Secret<String>,
+    subscription_token_ttl: chrono::Duration,
+    shutdown_timeout: std::time::Duration,
+    delivery_settings: DeliverySettings,
+    webhook_credentials: Credentials,
 ) -> Result<Server, anyhow::Error> {
     // TODO: https://stackoverflow.com/questions/71497831/is-there-a-way-to-split-server-routes-declaration-in-actix-web
     // Wraps it in an Arc
     let conn = web::Data::new(db_pool);
-    let e_client = web::Data::new(email_client);
     let base_url = web::Data::new(ApplicationBaseUrl(base_url.clone()));
+    let hmac = web::Data::new(HmacSecret(hmac_secret.clone()));
+    let token_ttl = web::Data::new(SubscriptionTokenTtl(subscription_token_ttl));
+    let delivery_settings = web::Data::new(delivery_settings);
+    let webhook_credentials = web::Data::new(WebhookCredentials(webhook_credentials));
     let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
     let cookie_store = CookieMessageStore::builder(secret_key.clone()).build();
     let message_framework = FlashMessagesFramework::builder(cookie_store).build();
@@ -90,8 +116,11 @@ async fn run(
                     .build(),
             )
             .app_data(conn.clone())
-            .app_data(e_client.clone())
             .app_data(base_url.clone())
+            .app_data(hmac.clone())
+            .app_data(token_ttl.clone())
+            .app_data(delivery_settings.clone())
+            .app_data(webhook_credentials.clone())
             .service(
                 web::scope("/admin")
                     .wrap(from_fn(reject_anonymous_users))
@@ -100,16 +129,33 @@ async fn run(
                     .service(change_password)
                     .service(log_out)
                     .service(publish_newsletter)
-                    .service(get_news_letter_form),
+                    .service(get_news_letter_form)
+                    .service(list_deliveries)
+                    .service(list_delivery_failures)
+                    .service(retry_delivery_failures)
+                    .service(retry_all_delivery_failures)
+                    .service(list_suppressions)
+                    .service(add_suppression)
+                    .service(remove_suppression),
             )
             .service(home)
             .service(login_form)
             .service(login)
             .service(subscriptions)
             .service(health_check)
+            .service(confirm_form)
             .service(confirm)
+            .service(resend_confirmation)
+            .service(unsubscribe_form)
+            .service(unsubscribe)
+            .service(track_open)
+            .service(track_click)
+            .service(postmark_webhook)
     })
     .listen(listener)?
+    // Signals are handled by `main`, which shuts the workers down along with the server.
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs())
     .run();
 
     Ok(server)
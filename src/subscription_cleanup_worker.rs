@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use crate::{configuration::Settings, shutdown::Shutdown, startup::get_connection_pool};
+use chrono::Utc;
+use sqlx::PgPool;
+
+pub struct CleanupOutcome {
+    pub deleted_tokens: u64,
+    pub deleted_subscriptions: u64,
+}
+
+/// Deletes tokens older than `token_retention` and the pending subscriptions left without any.
+#[tracing::instrument(skip(pool))]
+pub async fn delete_expired_subscriptions(
+    pool: &PgPool,
+    token_retention: chrono::Duration,
+) -> Result<CleanupOutcome, anyhow::Error> {
+    let cutoff = Utc::now() - token_retention;
+    let mut transaction = pool.begin().await?;
+    let deleted_tokens = sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE created_at < $1"#,
+        cutoff
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+
+    // A pending subscription without any token left can never be confirmed.
+    let deleted_subscriptions = sqlx::query!(
+        r#"
+        DELETE FROM subscriptions s
+        WHERE
+            s.status = 'pending_confirmation' AND
+            s.subscribed_at < $1 AND
+            NOT EXISTS (
+                SELECT 1 FROM subscription_tokens t WHERE t.subscription_id = s.id
+            )
+        "#,
+        cutoff
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    transaction.commit().await?;
+
+    Ok(CleanupOutcome {
+        deleted_tokens,
+        deleted_subscriptions,
+    })
+}
+
+async fn cleanup_loop(
+    pool: PgPool,
+    token_retention: chrono::Duration,
+    mut shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
+    while !shutdown.is_requested() {
+        match delete_expired_subscriptions(&pool, token_retention).await {
+            Ok(outcome) => {
+                tracing::info!(
+                    deleted_tokens = outcome.deleted_tokens,
+                    deleted_subscriptions = outcome.deleted_subscriptions,
+                    "Removed expired confirmation tokens"
+                );
+            }
+            Err(e) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to remove expired confirmation tokens",
+                );
+            }
+        }
+        shutdown.sleep(Duration::from_secs(60 * 60)).await;
+    }
+    Ok(())
+}
+
+pub async fn run_cleanup_worker_until_stopped(
+    configuration: Settings,
+    shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration);
+    cleanup_loop(
+        connection_pool,
+        configuration.application.subscription_token_retention(),
+        shutdown,
+    )
+    .await
+}
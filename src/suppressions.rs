@@ -0,0 +1,88 @@
+use sqlx::PgExecutor;
+use std::collections::HashSet;
+
+/// An address the provider refuses to deliver to.
+pub const SOURCE_PROVIDER: &str = "provider";
+/// A bounce or spam complaint reported by Postmark.
+pub const SOURCE_WEBHOOK: &str = "postmark_webhook";
+/// Added by hand from the admin pages.
+pub const SOURCE_ADMIN: &str = "admin";
+
+/// Suppressions are keyed by this form, so `Ursula@Example.com ` and `ursula@example.com` are one entry.
+pub fn normalise_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Adds `email` to the list. An existing entry keeps its original reason and source.
+#[tracing::instrument(skip(executor))]
+pub async fn suppress<'c>(
+    executor: impl PgExecutor<'c>,
+    email: &str,
+    reason: &str,
+    source: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO suppressions (email, reason, source, created_at)
+        VALUES ($1, $2, $3, now())
+        ON CONFLICT (email) DO NOTHING
+        "#,
+        normalise_email(email),
+        reason,
+        source,
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+#[tracing::instrument(skip(executor))]
+pub async fn unsuppress<'c>(
+    executor: impl PgExecutor<'c>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"DELETE FROM suppressions WHERE email = $1"#,
+        normalise_email(email),
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+#[tracing::instrument(skip(executor))]
+pub async fn is_suppressed<'c>(
+    executor: impl PgExecutor<'c>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT EXISTS(SELECT 1 FROM suppressions WHERE email = $1) AS "suppressed!""#,
+        normalise_email(email),
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(row.suppressed)
+}
+
+/// Which of `emails` are on the list, as they were passed in.
+#[tracing::instrument(skip_all)]
+pub async fn suppressed_among<'c>(
+    executor: impl PgExecutor<'c>,
+    emails: &[String],
+) -> Result<HashSet<String>, sqlx::Error> {
+    let normalised: Vec<_> = emails.iter().map(|email| normalise_email(email)).collect();
+    let suppressed: HashSet<_> = sqlx::query!(
+        r#"SELECT email FROM suppressions WHERE email = ANY($1)"#,
+        &normalised,
+    )
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .map(|row| row.email)
+    .collect();
+    Ok(emails
+        .iter()
+        .filter(|email| suppressed.contains(&normalise_email(email)))
+        .cloned()
+        .collect())
+}
@@ -0,0 +1,283 @@
+use base64::{engine::general_purpose, Engine};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use uuid::Uuid;
+
+/// A transparent 1x1 GIF.
+pub const OPEN_PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+fn open_mac(
+    secret: &Secret<String>,
+    newsletter_issue_id: &Uuid,
+    subscription_id: &Uuid,
+) -> Hmac<sha2::Sha256> {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.expose_secret().as_bytes()).unwrap();
+    mac.update(format!("open={newsletter_issue_id}:{subscription_id}").as_bytes());
+    mac
+}
+
+/// Identifies one recipient of one issue without putting their address in the URL.
+pub fn open_token(
+    secret: &Secret<String>,
+    newsletter_issue_id: &Uuid,
+    subscription_id: &Uuid,
+) -> String {
+    let tag = open_mac(secret, newsletter_issue_id, subscription_id)
+        .finalize()
+        .into_bytes();
+    format!("{newsletter_issue_id}.{subscription_id}.{tag:x}")
+}
+
+/// Returns the issue and subscription ids a token was issued for.
+pub fn verify_open_token(
+    secret: &Secret<String>,
+    token: &str,
+) -> Result<(Uuid, Uuid), anyhow::Error> {
+    let mut parts = token.split('.');
+    let (Some(newsletter_issue_id), Some(subscription_id), Some(tag), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("Malformed open tracking token");
+    };
+    let newsletter_issue_id = Uuid::parse_str(newsletter_issue_id)?;
+    let subscription_id = Uuid::parse_str(subscription_id)?;
+    open_mac(secret, &newsletter_issue_id, &subscription_id).verify_slice(&hex::decode(tag)?)?;
+    Ok((newsletter_issue_id, subscription_id))
+}
+
+pub fn open_pixel_url(
+    base_url: &str,
+    secret: &Secret<String>,
+    newsletter_issue_id: &Uuid,
+    subscription_id: &Uuid,
+) -> String {
+    let token = open_token(secret, newsletter_issue_id, subscription_id);
+    format!("{base_url}/t/o/{token}.gif")
+}
+
+/// Puts the tracking image at the end of the body, or of the content if it has no `<body>`.
+pub fn add_open_pixel(html_content: &str, pixel_url: &str) -> String {
+    let pixel = format!(
+        r#"<img src="{}" width="1" height="1" alt="" style="display:none">"#,
+        htmlescape::encode_minimal(pixel_url)
+    );
+    match html_content.to_ascii_lowercase().rfind("</body>") {
+        Some(end) => format!("{}{pixel}{}", &html_content[..end], &html_content[end..]),
+        None => format!("{html_content}{pixel}"),
+    }
+}
+
+fn click_mac(
+    secret: &Secret<String>,
+    newsletter_issue_id: &Uuid,
+    subscription_id: &Uuid,
+    url: &str,
+) -> Hmac<sha2::Sha256> {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.expose_secret().as_bytes()).unwrap();
+    mac.update(format!("click={newsletter_issue_id}:{subscription_id}:{url}").as_bytes());
+    mac
+}
+
+/// Where a tracked link leads and who followed it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ClickTarget {
+    pub newsletter_issue_id: Uuid,
+    pub subscription_id: Uuid,
+    pub url: String,
+}
+
+/// The target URL travels in the token itself, signed, so the endpoint only ever
+/// redirects to links we put in an issue.
+pub fn click_token(
+    secret: &Secret<String>,
+    newsletter_issue_id: &Uuid,
+    subscription_id: &Uuid,
+    url: &str,
+) -> String {
+    let tag = click_mac(secret, newsletter_issue_id, subscription_id, url)
+        .finalize()
+        .into_bytes();
+    let url = general_purpose::URL_SAFE_NO_PAD.encode(url);
+    format!("{newsletter_issue_id}.{subscription_id}.{url}.{tag:x}")
+}
+
+pub fn verify_click_token(
+    secret: &Secret<String>,
+    token: &str,
+) -> Result<ClickTarget, anyhow::Error> {
+    let mut parts = token.split('.');
+    let (Some(newsletter_issue_id), Some(subscription_id), Some(url), Some(tag), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        anyhow::bail!("Malformed click tracking token");
+    };
+    let newsletter_issue_id = Uuid::parse_str(newsletter_issue_id)?;
+    let subscription_id = Uuid::parse_str(subscription_id)?;
+    let url = String::from_utf8(general_purpose::URL_SAFE_NO_PAD.decode(url)?)?;
+    click_mac(secret, &newsletter_issue_id, &subscription_id, &url)
+        .verify_slice(&hex::decode(tag)?)?;
+    Ok(ClickTarget {
+        newsletter_issue_id,
+        subscription_id,
+        url,
+    })
+}
+
+pub fn click_url(
+    base_url: &str,
+    secret: &Secret<String>,
+    newsletter_issue_id: &Uuid,
+    subscription_id: &Uuid,
+    url: &str,
+) -> String {
+    let token = click_token(secret, newsletter_issue_id, subscription_id, url);
+    format!("{base_url}/t/c/{token}")
+}
+
+/// Replaces every absolute `http(s)` link in `href` attributes with `tracked_url(link)`.
+/// Anchors, `mailto:` links and the like are left alone.
+pub fn rewrite_links(html_content: &str, mut tracked_url: impl FnMut(&str) -> String) -> String {
+    // ASCII lowercasing keeps byte offsets, so positions found here apply to the original.
+    let lowercase = html_content.to_ascii_lowercase();
+    let mut rewritten = String::with_capacity(html_content.len());
+    let mut copied_up_to = 0;
+    let mut search_from = 0;
+    while let Some(offset) = lowercase[search_from..].find("href=") {
+        let attribute_start = search_from + offset;
+        search_from = attribute_start + "href=".len();
+        // Skip `data-href=` and friends.
+        if !lowercase[..attribute_start].ends_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let Some(quote) = html_content[search_from..]
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+        else {
+            continue;
+        };
+        let value_start = search_from + 1;
+        let Some(length) = html_content[value_start..].find(quote) else {
+            break;
+        };
+        let value_end = value_start + length;
+        let value = &html_content[value_start..value_end];
+        let url = htmlescape::decode_html(value).unwrap_or_else(|_| value.to_owned());
+        let lowercase_url = url.to_ascii_lowercase();
+        if lowercase_url.starts_with("http://") || lowercase_url.starts_with("https://") {
+            rewritten.push_str(&html_content[copied_up_to..value_start]);
+            rewritten.push_str(&htmlescape::encode_minimal(&tracked_url(&url)));
+            copied_up_to = value_end;
+        }
+        search_from = value_end;
+    }
+    rewritten.push_str(&html_content[copied_up_to..]);
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_open_pixel, click_token, open_token, rewrite_links, verify_click_token,
+        verify_open_token, ClickTarget,
+    };
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn secret() -> Secret<String> {
+        Secret::new("a-secret-only-the-server-knows".into())
+    }
+
+    #[test]
+    fn open_tokens_round_trip() {
+        let issue_id = Uuid::new_v4();
+        let subscription_id = Uuid::new_v4();
+
+        let token = open_token(&secret(), &issue_id, &subscription_id);
+
+        assert_eq!(
+            assert_ok!(verify_open_token(&secret(), &token)),
+            (issue_id, subscription_id)
+        );
+    }
+
+    #[test]
+    fn open_tokens_for_another_subscriber_are_rejected() {
+        let issue_id = Uuid::new_v4();
+        let token = open_token(&secret(), &issue_id, &Uuid::new_v4());
+        let tag = token.rsplit('.').next().unwrap();
+
+        let forged = format!("{issue_id}.{}.{tag}", Uuid::new_v4());
+
+        assert_err!(verify_open_token(&secret(), &forged));
+    }
+
+    #[test]
+    fn the_pixel_goes_right_before_the_closing_body_tag() {
+        let html = add_open_pixel(
+            "<html><BODY><p>Hi</p></BODY></html>",
+            "https://x.test/t/o/a.gif",
+        );
+
+        assert!(html.starts_with("<html><BODY><p>Hi</p><img src=\"https://x.test/t/o/a.gif\""));
+        assert!(html.ends_with("></BODY></html>"));
+    }
+
+    #[test]
+    fn click_tokens_round_trip() {
+        let issue_id = Uuid::new_v4();
+        let subscription_id = Uuid::new_v4();
+        let url = "https://example.com/post?id=1&ref=newsletter#comments";
+
+        let token = click_token(&secret(), &issue_id, &subscription_id, url);
+
+        assert_eq!(
+            assert_ok!(verify_click_token(&secret(), &token)),
+            ClickTarget {
+                newsletter_issue_id: issue_id,
+                subscription_id,
+                url: url.into(),
+            }
+        );
+    }
+
+    #[test]
+    fn click_tokens_cannot_be_pointed_at_another_url() {
+        let token = click_token(
+            &secret(),
+            &Uuid::new_v4(),
+            &Uuid::new_v4(),
+            "https://example.com",
+        );
+        let mut parts: Vec<_> = token.split('.').collect();
+        let evil = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            "https://evil.example",
+        );
+        parts[2] = &evil;
+
+        assert_err!(verify_click_token(&secret(), &parts.join(".")));
+    }
+
+    #[test]
+    fn only_absolute_web_links_are_rewritten() {
+        let html = rewrite_links(
+            r##"<a href="https://example.com/?a=1&amp;b=2">Post</a> <a HREF='mailto:me@example.com'>Mail</a> <a href="#top">Top</a> <div data-href="https://example.com">"##,
+            |url| format!("https://t.test/{}", url.len()),
+        );
+
+        assert_eq!(
+            html,
+            r##"<a href="https://t.test/28">Post</a> <a HREF='mailto:me@example.com'>Mail</a> <a href="#top">Top</a> <div data-href="https://example.com">"##
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use crate::helpers::{assert_is_redirected_to, spawn_app};
+use crate::news_letter::create_confirmed_customers;
+use news_letter::issue_delivery_worker::try_execute_task;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_deliveries() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/admin/deliveries", app.address))
+        .send()
+        .await
+        .unwrap();
+
+    assert_is_redirected_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn every_attempt_is_logged_with_the_provider_message_id() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    let guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails().await;
+    drop(guard);
+    sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+            "ErrorCode": 0,
+            "Message": "OK"
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    let deliveries = sqlx::query!(
+        "SELECT attempt, outcome, error, provider_message_id FROM email_deliveries ORDER BY attempt"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!(deliveries[0].attempt, 1);
+    assert_eq!(deliveries[0].outcome, "transient_failure");
+    assert!(deliveries[0].error.is_some());
+    assert_eq!(deliveries[1].attempt, 2);
+    assert_eq!(deliveries[1].outcome, "sent");
+    assert_eq!(
+        deliveries[1].provider_message_id.as_deref(),
+        Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+    );
+}
+
+#[tokio::test]
+async fn deliveries_can_be_looked_up_per_subscriber() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+            "ErrorCode": 0,
+            "Message": "OK"
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails().await;
+    let email = app.subscriber_email().await;
+
+    let html_page = app
+        .get_deliveries_html(&format!("subscriber_email={email}"))
+        .await;
+    assert!(html_page.contains("Newsletter title"));
+    assert!(html_page.contains("0a129aee-e1cd-480d-b08d-4f48548ff48d"));
+
+    let html_page = app
+        .get_deliveries_html("subscriber_email=someone-else%40example.com")
+        .await;
+    assert!(html_page.contains("No delivery attempts match."));
+}
+
+#[tokio::test]
+async fn deliveries_fail_over_to_the_next_provider_and_are_tagged_with_it() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    let backup_server = MockServer::start().await;
+    let mut configuration = app.configuration();
+    let mut backup = configuration.email_client.provider.clone();
+    backup.name = Some("backup".into());
+    backup.base_url = Some(backup_server.uri());
+    configuration.email_client.fallback_providers = vec![backup];
+    let email_client = configuration.email_client.client();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+            "ErrorCode": 0,
+            "Message": "OK"
+        })))
+        .expect(1)
+        .mount(&backup_server)
+        .await;
+    app.publish_newsletter().await;
+    try_execute_task(
+        &app.db_pool,
+        &email_client,
+        &app.base_url,
+        &app.hmac_secret,
+        &app.delivery,
+        "test-worker",
+    )
+    .await
+    .unwrap();
+
+    let delivery = sqlx::query!("SELECT outcome, provider FROM email_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(delivery.outcome, "sent");
+    assert_eq!(delivery.provider.as_deref(), Some("backup"));
+    let html_page = app.get_deliveries_html("").await;
+    assert!(html_page.contains("<td>backup</td>"));
+}
@@ -0,0 +1,137 @@
+use crate::helpers::{assert_is_redirected_to, spawn_app, TestApp};
+use crate::news_letter::create_confirmed_customers;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+/// Publishes an issue whose only delivery fails on its last allowed attempt.
+async fn create_delivery_failure(app: &TestApp, title: &str) {
+    let guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.publish_newsletter_with(serde_json::json!({ "title": title }))
+        .await;
+    sqlx::query!(
+        "UPDATE issue_delivery_queue SET n_retries = $1",
+        app.delivery.max_attempts - 1
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+    drop(guard);
+}
+
+async fn get_failure_ids(app: &TestApp) -> Vec<Uuid> {
+    sqlx::query!("SELECT failure_id FROM issue_delivery_failures ORDER BY failure_id")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.failure_id)
+        .collect()
+}
+
+async fn queued_deliveries(app: &TestApp) -> i64 {
+    sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+        .unwrap()
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_delivery_failures() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/admin/delivery-failures", app.address))
+        .send()
+        .await
+        .unwrap();
+
+    assert_is_redirected_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn delivery_failures_are_listed_per_issue() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    create_delivery_failure(&app, "First issue").await;
+    create_delivery_failure(&app, "Second issue").await;
+
+    let html = app.get_delivery_failures_html().await;
+
+    assert!(html.contains("<h2>First issue</h2>"));
+    assert!(html.contains("<h2>Second issue</h2>"));
+    assert!(html.contains("500 Internal Server Error"));
+    for failure_id in get_failure_ids(&app).await {
+        assert!(html.contains(&failure_id.to_string()));
+    }
+}
+
+#[tokio::test]
+async fn retrying_selected_failures_puts_only_them_back_in_the_queue() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    create_delivery_failure(&app, "First issue").await;
+    create_delivery_failure(&app, "Second issue").await;
+    let failure_ids = get_failure_ids(&app).await;
+
+    let response = app
+        .post_retry_delivery_failures(&[("failure_id", failure_ids[0].to_string())])
+        .await;
+
+    assert_is_redirected_to(&response, "/admin/delivery-failures");
+    assert_eq!(queued_deliveries(&app).await, 1);
+    assert_eq!(get_failure_ids(&app).await, vec![failure_ids[1]]);
+}
+
+#[tokio::test]
+async fn retrying_all_failures_puts_every_failure_back_in_the_queue() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    create_delivery_failure(&app, "First issue").await;
+    create_delivery_failure(&app, "Second issue").await;
+
+    let response = app.post_retry_all_delivery_failures().await;
+
+    assert_is_redirected_to(&response, "/admin/delivery-failures");
+    assert_eq!(queued_deliveries(&app).await, 2);
+    assert!(get_failure_ids(&app).await.is_empty());
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn failures_of_subscribers_who_are_no_longer_confirmed_are_not_retried() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    create_delivery_failure(&app, "First issue").await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_retry_all_delivery_failures().await;
+
+    assert_is_redirected_to(&response, "/admin/delivery-failures");
+    assert_eq!(queued_deliveries(&app).await, 0);
+}
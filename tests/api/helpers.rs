@@ -1,12 +1,15 @@
 use news_letter::{
-    configuration::{get_configuration, DatabaseSettings},
+    configuration::{get_configuration, DatabaseSettings, DeliverySettings, Settings},
     email_client::EmailClient,
-    issue_delivery_worker::{try_execute_task, ExecutionOutcome},
+    email_outbox_worker::try_execute_outbox_task,
+    issue_delivery_worker::{try_execute_batch, try_execute_task, ExecutionOutcome},
+    shutdown::Shutdown,
     startup::{get_connection_pool, Application},
     telemetry::{get_subscriber, init_subscriber},
 };
 use once_cell::sync::Lazy;
 use reqwest::Url;
+use secrecy::Secret;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use tracing_subscriber::fmt::format;
 use uuid::Uuid;
@@ -21,6 +24,10 @@ pub struct TestApp {
     pub test_user: TestUser,
     pub api_client: reqwest::Client,
     pub email_client: EmailClient,
+    pub base_url: String,
+    pub hmac_secret: Secret<String>,
+    pub delivery: DeliverySettings,
+    pub database_name: String,
 }
 
 pub struct TestUser {
@@ -90,6 +97,41 @@ impl TestApp {
         }
     }
 
+    /// Submits the form rendered by the confirmation link, as a subscriber clicking the button would.
+    pub async fn post_confirmation(&self, confirmation_link: &Url) -> reqwest::Response {
+        let subscription_token = confirmation_link
+            .query_pairs()
+            .find(|(k, _)| k == "subscription_token")
+            .expect("No subscription token in the confirmation link")
+            .1
+            .into_owned();
+        self.api_client
+            .post(format!("{}/subscriptions/confirm", &self.address))
+            .form(&[("subscription_token", subscription_token)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub fn get_unsubscribe_link(&self, email_req: &wiremock::Request) -> Url {
+        let body: serde_json::Value = serde_json::from_slice(&email_req.body).unwrap();
+        let header = body["Headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|h| h["Name"] == "List-Unsubscribe")
+            .expect("No List-Unsubscribe header");
+        let raw_link = header["Value"]
+            .as_str()
+            .unwrap()
+            .trim_start_matches('<')
+            .trim_end_matches('>');
+        let mut unsubscribe_link = Url::parse(raw_link).unwrap();
+        assert_eq!(unsubscribe_link.host_str().unwrap(), "127.0.0.1");
+        unsubscribe_link.set_port(Some(self.app_port)).unwrap();
+        unsubscribe_link
+    }
+
     fn get_links(&self, s: &str) -> Url {
         let links: Vec<_> = linkify::LinkFinder::new()
             .links(s)
@@ -103,6 +145,21 @@ impl TestApp {
         confirmation_link
     }
 
+    pub async fn post_postmark_webhook(
+        &self,
+        body: &serde_json::Value,
+        username: &str,
+        password: &str,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/webhooks/postmark", &self.address))
+            .basic_auth(username, Some(password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub async fn post_news_letters(&self, form: &serde_json::Value) -> reqwest::Response {
         self.api_client
             .post(format!("{}/admin/newsletters", &self.address))
@@ -112,6 +169,39 @@ impl TestApp {
             .expect("Unable to send request")
     }
 
+    /// Publishes an issue with a placeholder title and body.
+    /// The test user must already be logged in.
+    pub async fn publish_newsletter(&self) {
+        self.publish_newsletter_with(serde_json::json!({})).await;
+    }
+
+    /// Like `publish_newsletter`, with the fields in `overrides` replacing
+    /// or extending the placeholder form, e.g. a title or `track_opens`.
+    pub async fn publish_newsletter_with(&self, overrides: serde_json::Value) {
+        let mut form = serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "idempotency_key": Uuid::new_v4().to_string(),
+        });
+        if let serde_json::Value::Object(overrides) = overrides {
+            for (field, value) in overrides {
+                form[field] = value;
+            }
+        }
+        let response = self.post_news_letters(&form).await;
+        assert_is_redirected_to(&response, "/admin/newsletters");
+    }
+
+    /// The email of the only subscriber in the database.
+    pub async fn subscriber_email(&self) -> String {
+        sqlx::query!("SELECT email FROM subscriptions")
+            .fetch_one(&self.db_pool)
+            .await
+            .unwrap()
+            .email
+    }
+
     async fn _get_test_user(&self) -> (String, String) {
         let row = sqlx::query!("SELECT username, password_hash FROM users LIMIT 1",)
             .fetch_one(&self.db_pool)
@@ -210,12 +300,165 @@ impl TestApp {
             .expect("Failed to execute request")
     }
 
+    pub async fn get_deliveries_html(&self, query: &str) -> String {
+        self.api_client
+            .get(format!("{}/admin/deliveries?{}", self.address, query))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_delivery_failures_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/delivery-failures", self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_retry_delivery_failures<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/delivery-failures/retry", self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_retry_all_delivery_failures(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/delivery-failures/retry-all",
+                self.address
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_suppressions_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/suppressions", self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_suppression<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/suppressions", self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_remove_suppression<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/suppressions/remove", self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn dispatch_all_pending_confirmation_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_outbox_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.delivery,
+                "test-worker",
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Waits until a worker listens on `channel`: from then on no notification on it gets lost.
+    pub async fn wait_until_listening(&self, channel: &str) {
+        let listen = format!(r#"LISTEN "{channel}""#);
+        for _ in 0..100 {
+            let listening = sqlx::query!(
+                r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM pg_stat_activity
+                    WHERE datname = current_database() AND query = $1
+                ) AS "listening!"
+                "#,
+                listen
+            )
+            .fetch_one(&self.db_pool)
+            .await
+            .unwrap()
+            .listening;
+            if listening {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("No worker started listening on {channel}");
+    }
+
+    /// The settings the application under test was built with, e.g. to run background workers against it.
+    pub fn configuration(&self) -> Settings {
+        let mut configuration = get_configuration().expect("Unable to read configuration files");
+        configuration.database.database_name = self.database_name.clone();
+        configuration.email_client.provider.base_url = Some(self.email_server.uri());
+        configuration
+    }
+
     pub async fn dispatch_all_pending_emails(&self) {
         loop {
-            if let ExecutionOutcome::EmptyQueue =
-                try_execute_task(&self.db_pool, &self.email_client)
-                    .await
-                    .unwrap()
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.base_url,
+                &self.hmac_secret,
+                &self.delivery,
+                "test-worker",
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    pub async fn dispatch_all_pending_emails_in_batches(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_batch(
+                &self.db_pool,
+                &self.email_client,
+                &self.base_url,
+                &self.hmac_secret,
+                &self.delivery,
+                "test-worker",
+            )
+            .await
+            .unwrap()
             {
                 break;
             }
@@ -257,7 +500,7 @@ pub async fn spawn_app() -> TestApp {
         config.database.database_name = Uuid::new_v4().to_string();
         // Use random os port
         config.application.port = 0;
-        config.email_client.base_url = email_server.uri();
+        config.email_client.provider.base_url = Some(email_server.uri());
         config
     };
 
@@ -268,7 +511,7 @@ pub async fn spawn_app() -> TestApp {
     configure_database(&configuration.database).await;
     let address = format!("http://127.0.0.1:{}", app.port());
     let application_port = app.port();
-    let _ = tokio::spawn(app.run_until_stopped());
+    let _ = tokio::spawn(app.run_until_stopped(Shutdown::never()));
     let db_pool = get_connection_pool(&configuration);
 
     let test_app = TestApp {
@@ -283,6 +526,10 @@ pub async fn spawn_app() -> TestApp {
             .build()
             .unwrap(),
         email_client: configuration.email_client.client(),
+        base_url: configuration.application.base_url.clone(),
+        hmac_secret: configuration.application.hmac_secret.clone(),
+        delivery: configuration.delivery.clone(),
+        database_name: configuration.database.database_name.clone(),
     };
     test_app.test_user.store(&test_app.db_pool).await;
     test_app
@@ -4,6 +4,12 @@ mod login;
 mod news_letter;
 mod subscription_confirm;
 mod subscriptions;
+mod unsubscribe;
 
 mod change_password;
 mod dashboard;
+mod deliveries;
+mod delivery_failures;
+mod suppressions;
+mod tracking;
+mod webhooks;
@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::helpers::{assert_is_redirected_to, spawn_app, ConfirmationLink, TestApp};
@@ -5,6 +6,12 @@ use actix_web_lab::test;
 use fake::faker::internet::en::SafeEmail;
 use fake::faker::name::en::Name;
 use fake::Fake;
+use news_letter::configuration::DomainRateLimitSettings;
+use news_letter::issue_delivery_worker::{
+    run_worker_until_stopped, try_execute_batch, try_execute_task, ExecutionOutcome,
+    DELIVERY_QUEUE_CHANNEL,
+};
+use news_letter::shutdown::{shutdown_channel, Shutdown};
 use wiremock::MockBuilder;
 use wiremock::{
     matchers::{any, method, path},
@@ -138,16 +145,14 @@ async fn unknown_user_is_rejected() {
     assert_is_redirected_to(&res, "/login");
 }
 
-async fn create_confirmed_customers(app: &TestApp) {
+pub async fn create_confirmed_customers(app: &TestApp) {
     let confirmation_link = create_unconfirmed_subscribers(&app).await;
-    let res = reqwest::get(format!("{}", confirmation_link.html))
-        .await
-        .unwrap();
+    let res = app.post_confirmation(&confirmation_link.html).await;
 
     assert_eq!(res.status().as_u16(), 200);
 }
 
-async fn create_unconfirmed_subscribers(app: &TestApp) -> ConfirmationLink {
+pub async fn create_unconfirmed_subscribers(app: &TestApp) -> ConfirmationLink {
     let name: String = Name().fake();
     let email: String = SafeEmail().fake();
     let body = serde_urlencoded::to_string(&serde_json::json!({
@@ -167,6 +172,7 @@ async fn create_unconfirmed_subscribers(app: &TestApp) -> ConfirmationLink {
         .await
         .error_for_status()
         .unwrap();
+    app.dispatch_all_pending_confirmation_emails().await;
 
     let email_request = &app
         .email_server
@@ -282,3 +288,824 @@ async fn transient_errors_do_not_cause_duplicate_deliveries_on_retries() {
     assert_eq!(response.status().as_u16(), 303);
     app.dispatch_all_pending_emails().await;
 }
+
+#[tokio::test]
+async fn failed_deliveries_are_retried_later() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_news_letters(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 303);
+    app.dispatch_all_pending_emails().await;
+
+    let task =
+        sqlx::query!("SELECT n_retries, execute_after > now() AS later FROM issue_delivery_queue")
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("The failed delivery should still be queued");
+    assert_eq!(task.n_retries, 1);
+    assert_eq!(task.later, Some(true));
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .named("Delivery retry")
+        .mount(&app.email_server)
+        .await;
+    sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+}
+
+#[tokio::test]
+async fn deliveries_are_dead_lettered_once_attempts_are_exhausted() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(500))
+        .expect(app.delivery.max_attempts as u64)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_news_letters(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 303);
+    for _ in 0..app.delivery.max_attempts {
+        sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+            .execute(&app.db_pool)
+            .await
+            .unwrap();
+        app.dispatch_all_pending_emails().await;
+    }
+
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+
+    let failure = sqlx::query!("SELECT n_attempts, provider_response FROM issue_delivery_failures")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The failed delivery should have been dead-lettered");
+    assert_eq!(failure.n_attempts, app.delivery.max_attempts);
+    assert_eq!(
+        failure.provider_response.as_deref(),
+        Some("500 Internal Server Error")
+    );
+}
+
+#[tokio::test]
+async fn inactive_recipients_are_suppressed_without_retrying() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_news_letters(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 303);
+    app.dispatch_all_pending_emails().await;
+
+    let failure = sqlx::query!("SELECT n_attempts FROM issue_delivery_failures")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The delivery should have been dead-lettered");
+    assert_eq!(failure.n_attempts, 1);
+    let subscriber = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscriber.status, "suppressed");
+}
+
+#[tokio::test]
+async fn configuration_errors_stop_delivery_and_keep_the_task() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(401).set_body_json(serde_json::json!({
+            "ErrorCode": 10,
+            "Message": "Bad or missing Server API token."
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_news_letters(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 303);
+    let outcome = try_execute_task(
+        &app.db_pool,
+        &app.email_client,
+        &app.base_url,
+        &app.hmac_secret,
+        &app.delivery,
+        "test-worker",
+    )
+    .await;
+    assert!(outcome.is_err());
+
+    let task = sqlx::query!("SELECT n_retries, leased_by FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The task should still be queued");
+    assert_eq!(task.n_retries, 0);
+    assert_eq!(task.leased_by, None);
+}
+
+#[tokio::test]
+async fn batches_deliver_an_issue_to_every_subscriber_in_one_call() {
+    let app = spawn_app().await;
+    for _ in 0..3 {
+        create_confirmed_customers(&app).await;
+    }
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            { "ErrorCode": 0, "Message": "OK" },
+            { "ErrorCode": 0, "Message": "OK" },
+            { "ErrorCode": 0, "Message": "OK" }
+        ])))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails_in_batches().await;
+
+    let batch = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let messages: Vec<serde_json::Value> = serde_json::from_slice(&batch.body).unwrap();
+    assert_eq!(messages.len(), 3);
+    assert!(messages
+        .iter()
+        .all(|m| m["Headers"][0]["Name"] == "List-Unsubscribe"));
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+}
+
+#[tokio::test]
+async fn failed_messages_in_a_batch_are_handled_individually() {
+    let app = spawn_app().await;
+    for _ in 0..3 {
+        create_confirmed_customers(&app).await;
+    }
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            { "ErrorCode": 0, "Message": "OK" },
+            { "ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive." },
+            { "ErrorCode": 0, "Message": "OK" }
+        ])))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails_in_batches().await;
+
+    let batch = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let messages: Vec<serde_json::Value> = serde_json::from_slice(&batch.body).unwrap();
+    let failure = sqlx::query!("SELECT subscriber_email FROM issue_delivery_failures")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The rejected message should have been dead-lettered");
+    assert_eq!(messages[1]["To"], failure.subscriber_email.as_str());
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+}
+
+#[tokio::test]
+async fn an_invalid_message_in_a_batch_does_not_hold_up_the_others() {
+    let app = spawn_app().await;
+    for _ in 0..3 {
+        create_confirmed_customers(&app).await;
+    }
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            { "ErrorCode": 0, "Message": "OK" },
+            { "ErrorCode": 300, "Message": "Invalid email request" },
+            { "ErrorCode": 0, "Message": "OK" }
+        ])))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    // Panics if the worker gives up on the whole queue.
+    app.dispatch_all_pending_emails_in_batches().await;
+
+    let outcomes: Vec<String> =
+        sqlx::query!("SELECT outcome FROM email_deliveries ORDER BY outcome")
+            .fetch_all(&app.db_pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.outcome)
+            .collect();
+    assert_eq!(outcomes, vec!["rejected", "sent", "sent"]);
+    let failure = sqlx::query!("SELECT subscriber_email FROM issue_delivery_failures")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The refused message should have been dead-lettered");
+    // Nothing says the address itself is bad.
+    let status = sqlx::query!(
+        "SELECT status FROM subscriptions WHERE email = $1",
+        failure.subscriber_email
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .status;
+    assert_eq!(status, "confirmed");
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+}
+
+#[tokio::test]
+async fn a_failed_batch_call_schedules_a_retry_for_every_message() {
+    let app = spawn_app().await;
+    for _ in 0..2 {
+        create_confirmed_customers(&app).await;
+    }
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails_in_batches().await;
+
+    let retries: Vec<i16> = sqlx::query!("SELECT n_retries FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.n_retries)
+        .collect();
+    assert_eq!(retries, vec![1, 1]);
+}
+
+#[tokio::test]
+async fn tasks_leased_by_another_worker_are_left_alone() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    sqlx::query!(
+        "UPDATE issue_delivery_queue \
+         SET leased_until = now() + interval '1 hour', leased_by = 'another-worker'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let task = sqlx::query!("SELECT leased_by FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The leased task should still be queued");
+    assert_eq!(task.leased_by.as_deref(), Some("another-worker"));
+}
+
+#[tokio::test]
+async fn expired_leases_are_reclaimed() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    // A worker that crashed mid-delivery never comes back to finish its task.
+    sqlx::query!(
+        "UPDATE issue_delivery_queue \
+         SET leased_until = now() - interval '1 second', leased_by = 'crashed-worker'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+}
+
+#[tokio::test]
+async fn concurrent_workers_deliver_each_task_exactly_once() {
+    let app = spawn_app().await;
+    for _ in 0..6 {
+        create_confirmed_customers(&app).await;
+    }
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(200)))
+        .expect(6)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    let mut configuration = app.configuration();
+    configuration.worker.concurrency = 3;
+    configuration.delivery.batch_size = 1;
+    let email_client = Arc::new(configuration.email_client.client());
+    let workers = tokio::spawn(run_worker_until_stopped(
+        configuration,
+        email_client,
+        Shutdown::never(),
+    ));
+
+    let mut remaining = Some(6);
+    for _ in 0..50 {
+        remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .count;
+        if remaining == Some(0) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    workers.abort();
+    assert_eq!(remaining, Some(0));
+}
+
+#[tokio::test]
+async fn a_send_that_outlasts_its_lease_is_not_claimed_by_another_worker() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(1000)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    let mut configuration = app.configuration();
+    configuration.worker.concurrency = 2;
+    configuration.delivery.lease_duration_milliseconds = 300;
+    let email_client = Arc::new(configuration.email_client.client());
+    let workers = tokio::spawn(run_worker_until_stopped(
+        configuration,
+        email_client,
+        Shutdown::never(),
+    ));
+
+    let mut remaining = Some(1);
+    for _ in 0..30 {
+        remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .count;
+        if remaining == Some(0) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    workers.abort();
+    assert_eq!(remaining, Some(0));
+}
+
+#[tokio::test]
+async fn retries_of_a_delivery_carry_the_same_idempotency_key() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails().await;
+    sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let requests = app.email_server.received_requests().await.unwrap();
+    let sends: Vec<serde_json::Value> = requests
+        .iter()
+        .filter(|r| r.url.path() == "/email")
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect();
+    // The confirmation email comes first.
+    let (first, retry) = (&sends[sends.len() - 2], &sends[sends.len() - 1]);
+    let idempotency_key = &first["Metadata"]["idempotency_key"];
+    assert!(idempotency_key.is_string());
+    assert_eq!(idempotency_key, &retry["Metadata"]["idempotency_key"]);
+    let message_id = |body: &serde_json::Value| {
+        body["Headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|h| h["Name"] == "Message-ID")
+            .map(|h| h["Value"].clone())
+    };
+    assert!(message_id(first).is_some());
+    assert_eq!(message_id(first), message_id(retry));
+}
+
+#[tokio::test]
+async fn a_delivery_sent_before_a_crash_is_not_sent_again() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    // The provider accepted the email, then the worker died before dequeuing the task.
+    sqlx::query!(
+        "INSERT INTO issue_delivery_guards \
+         (newsletter_issue_id, subscriber_email, idempotency_key, created_at, sent_at) \
+         SELECT newsletter_issue_id, subscriber_email, $1, now(), now() FROM issue_delivery_queue",
+        uuid::Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+}
+
+#[tokio::test]
+async fn idle_workers_are_woken_as_soon_as_an_issue_is_published() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let mut configuration = app.configuration();
+    configuration.worker.idle_poll_interval_milliseconds = 60_000;
+    let email_client = Arc::new(configuration.email_client.client());
+    let workers = tokio::spawn(run_worker_until_stopped(
+        configuration,
+        email_client,
+        Shutdown::never(),
+    ));
+    // Once the workers listen, a publish cannot slip past them while they sleep.
+    app.wait_until_listening(DELIVERY_QUEUE_CHANNEL).await;
+
+    app.publish_newsletter().await;
+
+    let mut remaining = Some(1);
+    for _ in 0..20 {
+        remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .count;
+        if remaining == Some(0) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    workers.abort();
+    assert_eq!(remaining, Some(0));
+}
+
+#[tokio::test]
+async fn idle_workers_wake_up_when_a_deferred_task_becomes_due() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    // Nothing notifies the workers when a retry or a deferred task becomes due.
+    sqlx::query!(
+        "UPDATE issue_delivery_queue SET execute_after = now() + interval '500 milliseconds'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let mut configuration = app.configuration();
+    configuration.worker.idle_poll_interval_milliseconds = 60_000;
+    let email_client = Arc::new(configuration.email_client.client());
+    let workers = tokio::spawn(run_worker_until_stopped(
+        configuration,
+        email_client,
+        Shutdown::never(),
+    ));
+
+    let mut remaining = Some(1);
+    for _ in 0..40 {
+        remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .count;
+        if remaining == Some(0) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    workers.abort();
+    assert_eq!(remaining, Some(0));
+}
+
+#[tokio::test]
+async fn on_shutdown_the_worker_finishes_its_current_send_and_stops() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    let (shutdown_trigger, shutdown) = shutdown_channel();
+    let configuration = app.configuration();
+    let email_client = Arc::new(configuration.email_client.client());
+    let workers = tokio::spawn(run_worker_until_stopped(
+        configuration,
+        email_client,
+        shutdown,
+    ));
+    // The lease is taken right before the send, which the mock holds for 500ms.
+    let mut claimed = false;
+    for _ in 0..20 {
+        claimed = sqlx::query!("SELECT leased_by FROM issue_delivery_queue")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .leased_by
+            .is_some();
+        if claimed {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(claimed, "The worker never claimed the task");
+
+    shutdown_trigger.trigger();
+
+    tokio::time::timeout(Duration::from_secs(2), workers)
+        .await
+        .expect("The worker did not stop")
+        .unwrap()
+        .unwrap();
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+}
+
+#[tokio::test]
+async fn recipients_over_their_domain_limit_are_deferred_without_blocking_others() {
+    let app = spawn_app().await;
+    for _ in 0..3 {
+        create_confirmed_customers(&app).await;
+    }
+    sqlx::query!(
+        "UPDATE subscriptions SET email = n || '@throttled.example' \
+         FROM (SELECT id, row_number() OVER () AS n FROM subscriptions LIMIT 2) t \
+         WHERE subscriptions.id = t.id"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            { "ErrorCode": 0, "Message": "OK" },
+            { "ErrorCode": 0, "Message": "OK" }
+        ])))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    let mut configuration = app.configuration();
+    configuration.email_client.domain_rate_limits = vec![DomainRateLimitSettings {
+        domain: "throttled.example".into(),
+        messages_per_second: 0.1,
+        burst: 1,
+    }];
+    let email_client = configuration.email_client.client();
+    let outcome = try_execute_batch(
+        &app.db_pool,
+        &email_client,
+        &app.base_url,
+        &app.hmac_secret,
+        &app.delivery,
+        "test-worker",
+    )
+    .await
+    .unwrap();
+    assert!(matches!(outcome, ExecutionOutcome::TaskCompleted));
+
+    let deferred = sqlx::query!(
+        "SELECT subscriber_email, n_retries, leased_by, execute_after > now() AS later \
+         FROM issue_delivery_queue"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("One throttled task should be left in the queue");
+    assert!(deferred.subscriber_email.ends_with("@throttled.example"));
+    assert_eq!(deferred.n_retries, 0);
+    assert_eq!(deferred.leased_by, None);
+    assert_eq!(deferred.later, Some(true));
+}
+
+#[tokio::test]
+async fn suppressed_recipients_do_not_use_up_the_budget_of_their_domain() {
+    let app = spawn_app().await;
+    for _ in 0..3 {
+        create_confirmed_customers(&app).await;
+    }
+    sqlx::query!(
+        "UPDATE subscriptions SET email = n || '@throttled.example' \
+         FROM (SELECT id, row_number() OVER () AS n FROM subscriptions) t \
+         WHERE subscriptions.id = t.id"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "INSERT INTO suppressions (email, reason, source, created_at) \
+         SELECT email, 'hard_bounce', 'admin', now() FROM subscriptions LIMIT 2"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.test_user.login(&app).await;
+
+    when_sending_an_email()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.publish_newsletter().await;
+    let mut configuration = app.configuration();
+    configuration.email_client.domain_rate_limits = vec![DomainRateLimitSettings {
+        domain: "throttled.example".into(),
+        messages_per_second: 0.1,
+        burst: 1,
+    }];
+    let email_client = configuration.email_client.client();
+    try_execute_batch(
+        &app.db_pool,
+        &email_client,
+        &app.base_url,
+        &app.hmac_secret,
+        &app.delivery,
+        "test-worker",
+    )
+    .await
+    .unwrap();
+
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, Some(0));
+    let guards = sqlx::query!(
+        "SELECT COUNT(*) AS count FROM issue_delivery_guards \
+         WHERE subscriber_email IN (SELECT email FROM suppressions)"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(guards.count, Some(0));
+}
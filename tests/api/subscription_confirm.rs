@@ -1,4 +1,5 @@
 use crate::helpers::spawn_app;
+use news_letter::subscription_cleanup_worker::delete_expired_subscriptions;
 use sqlx;
 use wiremock::{
     matchers::{method, path},
@@ -28,6 +29,7 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
 
     let confirmation_link = app.get_confirmation_links(&email_request);
@@ -37,6 +39,35 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
     assert_eq!(response.status().as_u16(), 200);
 }
 
+#[tokio::test]
+async fn following_the_confirmation_link_does_not_confirm_on_its_own() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request);
+
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"<form action="/subscriptions/confirm" method="post">"#));
+    assert!(html.contains("Confirm my subscription"));
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_confirmation_email_with_a_link() {
     let app = spawn_app().await;
@@ -49,6 +80,7 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
     let email_request: &wiremock::Request = &app.email_server.received_requests().await.unwrap()[0];
 
     let confirmation_link = app.get_confirmation_links(&email_request);
@@ -68,12 +100,12 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
     let confirmation_link = app.get_confirmation_links(&email_request);
 
-    reqwest::get(format!("{}", confirmation_link.html))
+    app.post_confirmation(&confirmation_link.html)
         .await
-        .unwrap()
         .error_for_status()
         .unwrap();
 
@@ -86,3 +118,300 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     assert_eq!(saved.name, "le guin");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[tokio::test]
+async fn an_expired_confirmation_link_offers_to_resend_the_email() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let expired_link = app.get_confirmation_links(email_request);
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '1 year'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(expired_link.html.clone()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 410);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(r#"action="/subscriptions/confirm/resend""#));
+
+    let token = expired_link
+        .html
+        .query_pairs()
+        .find(|(k, _)| k == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/confirm/resend", app.address))
+        .form(&[("subscription_token", token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_confirmation_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[1];
+    let new_link = app.get_confirmation_links(email_request);
+    assert_ne!(new_link.html, expired_link.html);
+    app.post_confirmation(&new_link.html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn no_new_confirmation_email_is_sent_to_an_address_suppressed_since_subscribing() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let expired_link = app.get_confirmation_links(email_request);
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '1 year'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!(
+        "INSERT INTO suppressions (email, reason, source, created_at) \
+         SELECT email, 'hard_bounce', 'webhook', now() FROM subscriptions"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let token = expired_link
+        .html
+        .query_pairs()
+        .find(|(k, _)| k == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/confirm/resend", app.address))
+        .form(&[("subscription_token", token)])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let n_queued = sqlx::query!(r#"SELECT count(*) AS "n!" FROM email_outbox"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_queued, 0);
+}
+
+#[tokio::test]
+async fn expired_links_still_offer_a_resend_after_cleanup() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let expired_link = app.get_confirmation_links(email_request);
+    let application = app.configuration().application;
+    let expired_at =
+        chrono::Utc::now() - application.subscription_token_ttl() - chrono::Duration::hours(1);
+    sqlx::query!("UPDATE subscription_tokens SET created_at = $1", expired_at)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let outcome =
+        delete_expired_subscriptions(&app.db_pool, application.subscription_token_retention())
+            .await
+            .unwrap();
+    assert_eq!(outcome.deleted_tokens, 0);
+
+    let response = reqwest::get(expired_link.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 410);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(r#"action="/subscriptions/confirm/resend""#));
+}
+
+#[tokio::test]
+async fn cleanup_removes_expired_tokens_and_stale_pending_subscriptions() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.post_subscriptions("name=Ursula&email=ursula%40gmail.com".into())
+        .await;
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = now() - interval '1 year' WHERE email = 'ursula_le_guin@gmail.com'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "UPDATE subscription_tokens SET created_at = now() - interval '1 year' \
+        WHERE subscription_id = (SELECT id FROM subscriptions WHERE email = 'ursula_le_guin@gmail.com')"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let outcome = delete_expired_subscriptions(&app.db_pool, chrono::Duration::hours(48))
+        .await
+        .unwrap();
+    assert_eq!(outcome.deleted_tokens, 1);
+    assert_eq!(outcome.deleted_subscriptions, 1);
+
+    let remaining = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].email, "ursula@gmail.com");
+}
+
+#[tokio::test]
+async fn an_unknown_confirmation_token_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=unknown",
+        &app.address
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("We could not find a subscription for this confirmation link."));
+}
+
+#[tokio::test]
+async fn clicking_the_confirmation_link_twice_shows_already_confirmed_page() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request);
+
+    let response = app.post_confirmation(&confirmation_link.html).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains("You're confirmed!"));
+
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Your subscription has already been confirmed."));
+}
+
+#[tokio::test]
+async fn confirmation_responds_with_json_when_asked_to() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request);
+
+    let response = reqwest::Client::new()
+        .get(confirmation_link.html.clone())
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "pending_confirmation");
+
+    let subscription_token = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(k, _)| k == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/confirm", &app.address))
+        .header("Accept", "application/json")
+        .form(&[("subscription_token", subscription_token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "confirmed");
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token=unknown",
+            &app.address
+        ))
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "unknown_token");
+}
@@ -1,4 +1,8 @@
 use crate::helpers::spawn_app;
+use news_letter::email_outbox_worker::{run_outbox_worker_until_stopped, OUTBOX_CHANNEL};
+use news_letter::shutdown::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
 use wiremock::{
     matchers::{method, path},
     Mock, ResponseTemplate,
@@ -103,6 +107,7 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
 
@@ -138,3 +143,202 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
 
     assert_eq!(500, response.status().as_u16());
 }
+
+#[tokio::test]
+async fn subscribing_twice_with_a_pending_address_resends_the_confirmation_email() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(200, response.status().as_u16());
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(200, response.status().as_u16());
+    app.dispatch_all_pending_confirmation_emails().await;
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_link = app.get_confirmation_links(&email_requests[0]);
+    let second_link = app.get_confirmation_links(&email_requests[1]);
+    assert_eq!(first_link.html, second_link.html);
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribing_with_a_confirmed_address_returns_200_without_sending_an_email() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request);
+    app.post_confirmation(&confirmation_link.html)
+        .await
+        .error_for_status()
+        .unwrap();
+    drop(mock_guard);
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribe_succeeds_when_the_email_provider_is_down() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(200, response.status().as_u16());
+
+    app.dispatch_all_pending_confirmation_emails().await;
+
+    // The failed email stays in the outbox and is retried later.
+    let outbox = sqlx::query!("SELECT recipient, n_retries FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.recipient, "ursula_le_guin@gmail.com");
+    assert_eq!(outbox.n_retries, 1);
+}
+
+#[tokio::test]
+async fn an_outbox_email_leased_by_another_worker_is_not_sent_again() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    // Another worker claimed the email and is still sending it.
+    sqlx::query!(
+        "UPDATE email_outbox SET leased_until = now() + interval '1 minute', leased_by = 'other-worker'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    app.dispatch_all_pending_confirmation_emails().await;
+
+    let outbox = sqlx::query!("SELECT COUNT(*) AS count FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.count, Some(1));
+}
+
+#[tokio::test]
+async fn an_inactive_recipient_suppresses_the_subscription() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_confirmation_emails().await;
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "suppressed");
+    let outbox = sqlx::query!("SELECT COUNT(*) AS count FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.count, Some(0));
+
+    // Subscribing again must not bounce off the provider a second time.
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(200, response.status().as_u16());
+    app.dispatch_all_pending_confirmation_emails().await;
+}
+
+#[tokio::test]
+async fn the_outbox_worker_is_woken_as_soon_as_someone_subscribes() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let mut configuration = app.configuration();
+    configuration.worker.idle_poll_interval_milliseconds = 60_000;
+    let email_client = Arc::new(configuration.email_client.client());
+    let worker = tokio::spawn(run_outbox_worker_until_stopped(
+        configuration,
+        email_client,
+        Shutdown::never(),
+    ));
+    app.wait_until_listening(OUTBOX_CHANNEL).await;
+
+    app.post_subscriptions(body.into()).await;
+
+    let mut remaining = Some(1);
+    for _ in 0..20 {
+        remaining = sqlx::query!("SELECT COUNT(*) AS count FROM email_outbox")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .count;
+        if remaining == Some(0) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    worker.abort();
+    assert_eq!(remaining, Some(0));
+}
@@ -0,0 +1,118 @@
+use crate::helpers::{assert_is_redirected_to, spawn_app};
+use crate::news_letter::create_confirmed_customers;
+use wiremock::{
+    matchers::{any, method, path},
+    Mock, ResponseTemplate,
+};
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_manage_suppressions() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_suppression(&serde_json::json!({
+            "email": "ursula@example.com",
+            "reason": "asked by phone",
+        }))
+        .await;
+
+    assert_is_redirected_to(&response, "/login");
+    let n_suppressions = sqlx::query!(r#"SELECT count(*) AS "n!" FROM suppressions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_suppressions, 0);
+}
+
+#[tokio::test]
+async fn suppressed_addresses_are_listed_and_not_sent_new_issues() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    let email = app.subscriber_email().await;
+
+    // The list is keyed by the normalised address, whatever the admin typed.
+    let response = app
+        .post_suppression(&serde_json::json!({
+            "email": format!(" {} ", email.to_uppercase()),
+            "reason": "asked by phone",
+        }))
+        .await;
+    assert_is_redirected_to(&response, "/admin/suppressions");
+    let html = app.get_suppressions_html().await;
+    assert!(html.contains(&email.to_lowercase()));
+    assert!(html.contains("asked by phone"));
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails().await;
+
+    let n_queued = sqlx::query!(r#"SELECT count(*) AS "n!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_queued, 0);
+}
+
+#[tokio::test]
+async fn suppressed_addresses_are_not_sent_confirmation_emails() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    app.post_suppression(&serde_json::json!({
+        "email": "ursula_le_guin@gmail.com",
+        "reason": "spam trap",
+    }))
+    .await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_confirmation_emails().await;
+
+    let n_outbox = sqlx::query!(r#"SELECT count(*) AS "n!" FROM email_outbox"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_outbox, 0);
+}
+
+#[tokio::test]
+async fn removed_suppressions_are_sent_new_issues_again() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    let email = app.subscriber_email().await;
+    app.post_suppression(&serde_json::json!({
+        "email": email,
+        "reason": "",
+    }))
+    .await;
+
+    let response = app
+        .post_remove_suppression(&serde_json::json!({ "email": email }))
+        .await;
+    assert_is_redirected_to(&response, "/admin/suppressions");
+    assert!(!app.get_suppressions_html().await.contains(&email));
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails().await;
+}
@@ -0,0 +1,184 @@
+use crate::helpers::{spawn_app, TestApp};
+use crate::news_letter::create_confirmed_customers;
+use reqwest::Url;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+const LINK: &str = "https://example.com/posts/1?utm_source=newsletter&lang=en";
+
+/// `tracking` lists the tracking checkboxes to tick, e.g. `track_opens`.
+async fn publish_newsletter(app: &TestApp, tracking: &[&str]) -> Uuid {
+    let mut form = serde_json::json!({
+        "html_content": format!(
+            r#"<html><body><p>Newsletter body as HTML</p><a href="{}">Read more</a></body></html>"#,
+            htmlescape::encode_minimal(LINK)
+        ),
+    });
+    for checkbox in tracking {
+        form[checkbox] = "true".into();
+    }
+    app.publish_newsletter_with(form).await;
+    sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id
+}
+
+/// Sends the issue and returns the HTML body the subscriber received.
+async fn deliver(app: &TestApp) -> String {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+    let requests = app.email_server.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    body["HtmlBody"].as_str().unwrap().to_owned()
+}
+
+fn open_pixel_link(app: &TestApp, html: &str) -> Option<Url> {
+    let start = html.find(r#"<img src=""#)? + r#"<img src=""#.len();
+    let end = start + html[start..].find('"')?;
+    let mut link = Url::parse(&html[start..end]).unwrap();
+    assert!(link.path().starts_with("/t/o/"));
+    link.set_port(Some(app.app_port)).unwrap();
+    Some(link)
+}
+
+fn tracked_link(app: &TestApp, html: &str) -> Url {
+    let start = html.find(r#"<a href=""#).unwrap() + r#"<a href=""#.len();
+    let end = start + html[start..].find('"').unwrap();
+    let mut link = Url::parse(&html[start..end]).unwrap();
+    assert!(link.path().starts_with("/t/c/"));
+    link.set_port(Some(app.app_port)).unwrap();
+    link
+}
+
+#[tokio::test]
+async fn opens_are_recorded_and_feed_the_open_rate() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    let issue_id = publish_newsletter(&app, &["track_opens"]).await;
+
+    let html = deliver(&app).await;
+    let pixel = open_pixel_link(&app, &html).expect("No tracking image in the issue");
+    assert!(html.ends_with("</body></html>"));
+    for _ in 0..2 {
+        let response = reqwest::get(pixel.clone()).await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["Content-Type"], "image/gif");
+    }
+
+    let open = sqlx::query!("SELECT first_opened_at, last_opened_at, n_opens FROM issue_opens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(open.n_opens, 2);
+    assert!(open.first_opened_at < open.last_opened_at);
+    let html = app
+        .get_deliveries_html(&format!("newsletter_issue_id={issue_id}"))
+        .await;
+    assert!(html.contains("Opened by 1 of 1 recipients (100.0%)."));
+}
+
+#[tokio::test]
+async fn issues_published_without_open_tracking_carry_no_tracking_image() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    let issue_id = publish_newsletter(&app, &[]).await;
+
+    let html = deliver(&app).await;
+
+    assert!(open_pixel_link(&app, &html).is_none());
+    let html = app
+        .get_deliveries_html(&format!("newsletter_issue_id={issue_id}"))
+        .await;
+    assert!(html.contains("Opens are not tracked for this issue."));
+}
+
+#[tokio::test]
+async fn forged_open_tokens_are_rejected() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    publish_newsletter(&app, &["track_opens"]).await;
+    let html = deliver(&app).await;
+    let pixel = open_pixel_link(&app, &html).unwrap();
+
+    let forged = pixel.as_str().replace(".gif", "00.gif");
+    let response = reqwest::get(forged).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+    let n_opens = sqlx::query!(r#"SELECT count(*) AS "n!" FROM issue_opens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_opens, 0);
+}
+
+#[tokio::test]
+async fn clicks_are_recorded_and_redirected_to_the_original_link() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    let issue_id = publish_newsletter(&app, &["track_clicks"]).await;
+
+    let html = deliver(&app).await;
+    assert!(!html.contains("example.com"));
+    let link = tracked_link(&app, &html);
+    for _ in 0..2 {
+        let response = app.api_client.get(link.clone()).send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 302);
+        assert_eq!(response.headers()["Location"], LINK);
+    }
+
+    let html = app
+        .get_deliveries_html(&format!("newsletter_issue_id={issue_id}"))
+        .await;
+    assert!(html.contains(&format!(
+        "<tr><td>{}</td><td>2</td><td>1</td></tr>",
+        htmlescape::encode_minimal(LINK)
+    )));
+}
+
+#[tokio::test]
+async fn click_tokens_pointing_elsewhere_are_not_redirected() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    publish_newsletter(&app, &["track_clicks"]).await;
+    let html = deliver(&app).await;
+    let link = tracked_link(&app, &html);
+
+    let mut parts: Vec<_> = link
+        .path_segments()
+        .unwrap()
+        .last()
+        .unwrap()
+        .split('.')
+        .collect();
+    let evil = base64::Engine::encode(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+        "https://evil.example",
+    );
+    parts[2] = &evil;
+    let forged = format!("{}/t/c/{}", app.address, parts.join("."));
+    let response = app.api_client.get(forged).send().await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+    let n_clicks = sqlx::query!(r#"SELECT count(*) AS "n!" FROM link_clicks"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_clicks, 0);
+}
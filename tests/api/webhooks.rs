@@ -0,0 +1,172 @@
+use crate::helpers::{spawn_app, TestApp};
+use crate::news_letter::create_confirmed_customers;
+use secrecy::ExposeSecret;
+use uuid::Uuid;
+use wiremock::{matchers::any, Mock, ResponseTemplate};
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+fn bounce(record_type: &str, bounce_type: &str, email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": record_type,
+        "ID": 4323372036854775807_i64,
+        "Type": bounce_type,
+        "TypeCode": 1,
+        "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+        "Description": "The server was unable to deliver your message (ex: unknown user, mailbox not found).",
+        "Details": "smtp;550 5.1.1 The email account that you tried to reach does not exist.",
+        "Email": email,
+        "From": "sender@example.com",
+        "BouncedAt": "2026-10-17T16:33:54.9070259Z",
+        "Inactive": bounce_type != "SoftBounce",
+        "MessageStream": "outbound"
+    })
+}
+
+async fn post_webhook(app: &TestApp, body: &serde_json::Value) -> reqwest::Response {
+    let credentials = app.configuration().email_client.webhook;
+    app.post_postmark_webhook(
+        body,
+        &credentials.username,
+        credentials.password.expose_secret(),
+    )
+    .await
+}
+
+async fn subscribe_again(app: &TestApp, email: &str) {
+    let body = serde_urlencoded::to_string([("name", "le guin"), ("email", email)]).unwrap();
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_confirmation_emails().await;
+}
+
+#[tokio::test]
+async fn webhooks_without_valid_credentials_are_rejected() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    let email = app.subscriber_email().await;
+
+    let response = app
+        .post_postmark_webhook(
+            &bounce("Bounce", "HardBounce", &email),
+            "postmark",
+            &Uuid::new_v4().to_string(),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["WWW-Authenticate"],
+        r#"Basic realm="webhooks""#
+    );
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn a_hard_bounce_is_recorded_and_marks_the_subscriber_as_bounced() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    let email = app.subscriber_email().await;
+
+    let response = post_webhook(&app, &bounce("Bounce", "HardBounce", &email)).await;
+    // Postmark retries webhooks; a second copy must be harmless.
+    let retry = post_webhook(&app, &bounce("Bounce", "HardBounce", &email)).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(retry.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+    let bounces = sqlx::query!("SELECT record_type, bounce_type, email FROM email_bounces")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(bounces.len(), 1);
+    assert_eq!(bounces[0].record_type, "Bounce");
+    assert_eq!(bounces[0].bounce_type, "HardBounce");
+    assert_eq!(bounces[0].email, email);
+}
+
+#[tokio::test]
+async fn bounces_match_the_subscriber_whatever_the_case_of_the_address() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    let email = app.subscriber_email().await;
+
+    let response = post_webhook(&app, &bounce("Bounce", "HardBounce", &email.to_uppercase())).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn a_soft_bounce_is_recorded_but_the_subscriber_stays_confirmed() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    let email = app.subscriber_email().await;
+
+    let response = post_webhook(&app, &bounce("Bounce", "SoftBounce", &email)).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribers_who_complained_are_not_sent_new_issues() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    app.test_user.login(&app).await;
+    let email = app.subscriber_email().await;
+
+    let response = post_webhook(&app, &bounce("SpamComplaint", "SpamComplaint", &email)).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.publish_newsletter().await;
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn bounced_subscribers_are_not_sent_a_confirmation_email_when_subscribing_again() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    let email = app.subscriber_email().await;
+    post_webhook(&app, &bounce("Bounce", "HardBounce", &email)).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    subscribe_again(&app, &email).await;
+
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn subscribers_who_complained_are_not_sent_a_confirmation_email_when_subscribing_again() {
+    let app = spawn_app().await;
+    create_confirmed_customers(&app).await;
+    let email = app.subscriber_email().await;
+    post_webhook(&app, &bounce("SpamComplaint", "SpamComplaint", &email)).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    subscribe_again(&app, &email).await;
+
+    assert_eq!(subscriber_status(&app).await, "complained");
+}